@@ -24,7 +24,7 @@ pub async fn migrate(migration_dir: impl AsRef<Path>, entities: Entities, name:
 
 	let (up, down) = sql_gen.gen_migration().await?;
 
-	if up.len() > 0 {
+	if !up.is_empty() {
 		let now = Utc::now().format("%Y%m%d%H%M%S");
 		let migration_name = format!("{}_{}", now, name);
 
@@ -33,7 +33,7 @@ pub async fn migrate(migration_dir: impl AsRef<Path>, entities: Entities, name:
 		up_file.write_all(up.as_bytes()).await?;
 
 		if let Some(down) = down {
-			if down.len() > 0 {
+			if !down.is_empty() {
 				let mut down_file =
 					File::create(migration_dir.as_ref().join(format!("{}.down.sql", migration_name))).await?;
 				down_file.write_all(down.as_bytes()).await?;
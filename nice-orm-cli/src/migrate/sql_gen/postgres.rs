@@ -1,10 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use super::SqlGen;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use async_trait::async_trait;
 use itertools::Itertools;
-use nice_orm::entity_meta::{Entities, EntityMeta, FieldMeta, FieldType, IdentityGeneration};
+use nice_orm::entity_meta::{Entities, EntityMeta, FieldMeta, ForeignKey, IdentityGeneration, OnDelete};
 use sqlx::{migrate::Migrator, postgres::PgPoolOptions, query_as, FromRow, PgPool, Pool, Postgres};
 
 pub struct PostgresSqlGen {
@@ -13,25 +13,40 @@ pub struct PostgresSqlGen {
 }
 impl PostgresSqlGen {
 	pub async fn new(entities: Entities, uri: &str) -> Result<Self> {
-		Ok(Self { entities, pool: PgPoolOptions::new().connect(&uri).await? })
+		Ok(Self { entities, pool: PgPoolOptions::new().connect(uri).await? })
 	}
 
-	fn create_table(&self, entity: &EntityMeta) -> String {
+	/// Creates a table with only the given foreign keys, which have to reference tables that already exist, or itself.
+	fn create_table(&self, entity: &EntityMeta, foreign_keys: &[&ForeignKey]) -> String {
 		let mut lines = entity
 			.fields
 			.values()
 			.map(|field| {
-				let field_type = Self::entity_type_to_column_type(field.ty);
+				let field_type = field.ty.column_type();
 				let column_constraints = Self::make_column_constraints(field);
 				format!("\n\t\"{}\" {} {}", field.name, field_type, column_constraints)
 			})
 			.collect::<Vec<_>>();
 
-		if entity.primary_key.len() > 0 {
+		if !entity.primary_key.is_empty() {
 			let primary_key = entity.primary_key.iter().map(|field| format!("\"{}\"", field)).collect::<Vec<_>>();
 			lines.push(format!("\n\tPRIMARY KEY ({})", primary_key.join(", ")));
 		}
 
+		for foreign_key in foreign_keys {
+			let constraint = Self::foreign_key_constraint(
+				entity.table_name,
+				foreign_key.column,
+				foreign_key.table,
+				foreign_key.on_delete,
+			);
+			lines.push(format!("\n\t{}", constraint));
+		}
+
+		for columns in entity.unique {
+			lines.push(format!("\n\t{}", Self::unique_constraint(entity.table_name, columns)));
+		}
+
 		format!("CREATE TABLE \"{}\" ({}\n);\n", entity.table_name, lines.join(","))
 	}
 
@@ -40,7 +55,7 @@ impl PostgresSqlGen {
 	}
 
 	fn create_column(&self, table: &str, field: &FieldMeta) -> String {
-		let field_type = Self::entity_type_to_column_type(field.ty);
+		let field_type = field.ty.column_type();
 		let column_constraints = Self::make_column_constraints(field);
 		format!("ALTER TABLE \"{}\" ADD COLUMN \"{}\" {} {};", table, field.name, field_type, column_constraints)
 	}
@@ -50,8 +65,21 @@ impl PostgresSqlGen {
 	}
 
 	fn update_column(&self, table: &str, field: &FieldMeta) -> String {
-		let field_type = Self::entity_type_to_column_type(field.ty);
-		format!("ALTER TABLE \"{}\" ALTER COLUMN \"{}\" TYPE {};", table, field.name, field_type)
+		let field_type = field.ty.column_type();
+		if field.ty.enum_values().is_some() {
+			// postgres won't cast to an enum implicitly, even from text
+			format!(
+				"ALTER TABLE \"{}\" ALTER COLUMN \"{}\" TYPE {} USING \"{}\"::{};",
+				table, field.name, field_type, field.name, field_type
+			)
+		} else {
+			format!("ALTER TABLE \"{}\" ALTER COLUMN \"{}\" TYPE {};", table, field.name, field_type)
+		}
+	}
+
+	fn set_column_nullability(&self, table: &str, column: &str, optional: bool) -> String {
+		let action = if optional { "DROP" } else { "SET" };
+		format!("ALTER TABLE \"{}\" ALTER COLUMN \"{}\" {} NOT NULL;", table, column, action)
 	}
 
 	fn add_identity_generation(&self, table: &str, field: &FieldMeta) -> String {
@@ -62,13 +90,117 @@ impl PostgresSqlGen {
 		)
 	}
 
-	fn entity_type_to_column_type(ty: FieldType) -> &'static str {
-		match ty {
-			FieldType::I32 => "integer",
-			FieldType::String => "character varying",
+	fn add_foreign_key(&self, table: &str, column: &str, references: &str, on_delete: OnDelete) -> String {
+		let constraint = Self::foreign_key_constraint(table, column, references, on_delete);
+		format!("ALTER TABLE \"{}\" ADD {};", table, constraint)
+	}
+
+	fn drop_constraint(&self, table: &str, constraint: &str) -> String {
+		format!("ALTER TABLE \"{}\" DROP CONSTRAINT \"{}\";", table, constraint)
+	}
+
+	fn foreign_key_constraint(table: &str, column: &str, references: &str, on_delete: OnDelete) -> String {
+		format!(
+			"CONSTRAINT \"{}\" FOREIGN KEY (\"{}\") REFERENCES \"{}\" ON DELETE {}",
+			Self::foreign_key_name(table, column),
+			column,
+			references,
+			Self::on_delete(on_delete)
+		)
+	}
+
+	fn add_unique(&self, table: &str, columns: &[&str]) -> String {
+		format!("ALTER TABLE \"{}\" ADD {};", table, Self::unique_constraint(table, columns))
+	}
+
+	fn unique_constraint(table: &str, columns: &[&str]) -> String {
+		let quoted = columns.iter().map(|column| format!("\"{}\"", column)).collect::<Vec<_>>();
+		format!("CONSTRAINT \"{}\" UNIQUE ({})", Self::unique_name(table, columns), quoted.join(", "))
+	}
+
+	/// Matches the name postgres would pick by itself.
+	fn unique_name(table: &str, columns: &[&str]) -> String {
+		format!("{}_{}_key", table, columns.join("_"))
+	}
+
+	/// Matches the name postgres would pick by itself.
+	fn foreign_key_name(table: &str, column: &str) -> String {
+		format!("{}_{}_fkey", table, column)
+	}
+
+	fn on_delete(on_delete: OnDelete) -> &'static str {
+		match on_delete {
+			OnDelete::NoAction => "NO ACTION",
+			OnDelete::Restrict => "RESTRICT",
+			OnDelete::Cascade => "CASCADE",
+			OnDelete::SetNull => "SET NULL",
+			OnDelete::SetDefault => "SET DEFAULT",
 		}
 	}
 
+	/// Orders `tables` so each one comes after the tables its foreign keys reference, since those are created inline.
+	/// Tables that reference each other in a cycle can't all be, so within a cycle, some come before a table they
+	/// reference.
+	fn creation_order(&self, tables: &[&'static str]) -> Vec<&'static str> {
+		fn visit(
+			entities: Entities,
+			table: &'static str,
+			tables: &[&'static str],
+			visited: &mut HashSet<&'static str>,
+			order: &mut Vec<&'static str>,
+		) {
+			// also stops at cycles, which can't be created inline in any order
+			if !visited.insert(table) {
+				return;
+			}
+			for foreign_key in entities[table].foreign_keys {
+				if let Some(&referenced) = tables.iter().find(|&&x| x == foreign_key.table) {
+					visit(entities, referenced, tables, visited, order);
+				}
+			}
+			order.push(table);
+		}
+
+		let mut visited = HashSet::new();
+		let mut order = vec![];
+		for &table in tables {
+			visit(self.entities, table, tables, &mut visited, &mut order);
+		}
+		order
+	}
+
+	/// Splits the foreign keys of the table at `index` of a `creation_order` into those that reference tables created
+	/// after it, which only happen in cycles and are added once those exist, and those that can be created inline.
+	fn split_foreign_keys(
+		&self,
+		order: &[&'static str],
+		index: usize,
+	) -> (Vec<&'static ForeignKey>, Vec<&'static ForeignKey>) {
+		let foreign_keys = self.entities[order[index]].foreign_keys.iter();
+		foreign_keys.partition(|foreign_key| order[index + 1..].contains(&foreign_key.table))
+	}
+
+	fn create_enum(&self, name: &str, values: &[&str]) -> String {
+		let values = values.iter().map(|value| format!("'{}'", value)).collect::<Vec<_>>();
+		format!("CREATE TYPE \"{}\" AS ENUM ({});", name, values.join(", "))
+	}
+
+	fn drop_enum(&self, name: &str) -> String {
+		format!("DROP TYPE \"{}\";", name)
+	}
+
+	fn add_enum_value(&self, name: &str, value: &str, position: &str) -> String {
+		format!("ALTER TYPE \"{}\" ADD VALUE '{}'{};", name, value, position)
+	}
+
+	/// Collects the enum types used by every entity, by name.
+	fn enum_types(&self) -> BTreeMap<&'static str, &'static [&'static str]> {
+		let fields = self.entities.values().flat_map(|entity| entity.fields.values());
+		// arrays of an enum report the enum's values too
+		let name = |field: &FieldMeta| field.ty.column_type().trim_end_matches("[]");
+		fields.filter_map(|field| Some((name(field), field.ty.enum_values()?))).collect()
+	}
+
 	fn make_column_constraints(field: &FieldMeta) -> String {
 		let mut column_constraints = vec![];
 		if field.optional {
@@ -94,10 +226,79 @@ impl PostgresSqlGen {
 impl SqlGen for PostgresSqlGen {
 	async fn gen_migration(&self) -> Result<(String, Option<String>)> {
 		let old_schema = get_old_table_info(&self.pool).await?;
+		let old_enums = get_old_enum_info(&self.pool).await?;
+		let old_foreign_keys = get_old_foreign_key_info(&self.pool).await?;
+		let old_unique = get_old_unique_info(&self.pool).await?;
+		let enums = self.enum_types();
 
 		let mut up = vec![];
 		let mut down = Some(vec![]);
 
+		// create and extend enum types before any column can use them
+		for (&name, &values) in &enums {
+			let old_values = match old_enums.get(name) {
+				Some(old_values) => old_values,
+				None => {
+					up.push(self.create_enum(name, values));
+					if let Some(down) = &mut down {
+						down.push(self.drop_enum(name));
+					}
+					continue;
+				},
+			};
+
+			let removed = old_values.iter().filter(|&old| !values.contains(&&**old)).collect::<Vec<_>>();
+			if !removed.is_empty() {
+				bail!(
+					"Values {:?} were removed from enum type \"{}\". Postgres can't drop enum values, so this needs a \
+					 manual migration.",
+					removed,
+					name
+				);
+			}
+
+			for (i, &value) in values.iter().enumerate() {
+				if old_values.iter().any(|old| old == value) {
+					continue;
+				}
+				// keep the declaration order, since postgres compares enum values by it
+				let position = match i.checked_sub(1) {
+					Some(previous) => format!(" AFTER '{}'", values[previous]),
+					None => old_values.first().map(|first| format!(" BEFORE '{}'", first)).unwrap_or_default(),
+				};
+				up.push(self.add_enum_value(name, value, &position));
+				// postgres has no way to remove an enum value
+				down = None;
+			}
+		}
+
+		// drop foreign keys, first so they can't get in the way of dropping the tables they reference
+		for (table, old_foreign_keys) in &old_foreign_keys {
+			let foreign_keys = self.entities.get(&**table).map(|entity| entity.foreign_keys).unwrap_or_default();
+			for (name, old) in old_foreign_keys {
+				if !foreign_keys.iter().any(|x| Self::foreign_key_name(table, x.column) == *name && old == x) {
+					up.push(self.drop_constraint(table, name));
+					if let Some(down) = &mut down {
+						down.push(self.add_foreign_key(table, &old.column, &old.table, old.on_delete));
+					}
+				}
+			}
+		}
+
+		// drop unique constraints, before their columns are dropped or changed
+		for (table, old_unique) in &old_unique {
+			let unique = self.entities.get(&**table).map(|entity| entity.unique).unwrap_or_default();
+			for (name, old_columns) in old_unique {
+				if !unique.iter().any(|&columns| Self::unique_name(table, columns) == *name && old_columns == columns) {
+					up.push(self.drop_constraint(table, name));
+					if let Some(down) = &mut down {
+						let old_columns = old_columns.iter().map(|x| &**x).collect::<Vec<_>>();
+						down.push(self.add_unique(table, &old_columns));
+					}
+				}
+			}
+		}
+
 		// drop tables
 		for table in old_schema.keys().filter(|k| !self.entities.contains_key(k)) {
 			up.push(self.drop_table(table));
@@ -105,15 +306,25 @@ impl SqlGen for PostgresSqlGen {
 		}
 
 		// create tables
-		for table in self.entities.keys().filter(|&&k| !old_schema.contains_key(k)) {
-			let entity = self.entities[table];
-			up.push(self.create_table(entity));
+		let new_tables = self.entities.keys().copied().filter(|&k| !old_schema.contains_key(k)).collect::<Vec<_>>();
+		let order = self.creation_order(&new_tables);
+		let mut deferred_foreign_keys = vec![];
+		for (i, &table) in order.iter().enumerate() {
+			let (deferred, inline) = self.split_foreign_keys(&order, i);
+			up.push(self.create_table(self.entities[table], &inline));
 			if let Some(down) = &mut down {
 				down.push(self.drop_table(table));
 			}
+			deferred_foreign_keys.extend(deferred.into_iter().map(|foreign_key| (table, foreign_key)));
+		}
+		for (table, foreign_key) in deferred_foreign_keys {
+			up.push(self.add_foreign_key(table, foreign_key.column, foreign_key.table, foreign_key.on_delete));
+			if let Some(down) = &mut down {
+				down.push(self.drop_constraint(table, &Self::foreign_key_name(table, foreign_key.column)));
+			}
 		}
 
-		for (&table, &entity) in &*self.entities {
+		for (&table, &entity) in self.entities {
 			if let Some(old_fields) = old_schema.get(table) {
 				// drop columns
 				for column in old_fields.keys().filter(|k| !entity.fields.contains_key(k)) {
@@ -124,13 +335,19 @@ impl SqlGen for PostgresSqlGen {
 					let field_meta = &entity.fields[column];
 					if let Some(old_column) = old_fields.get(column) {
 						// update columns
-						if old_column.ty != Self::entity_type_to_column_type(field_meta.ty) {
-							up.push(self.update_column(table, &field_meta));
+						if old_column.ty != field_meta.ty.column_type() {
+							up.push(self.update_column(table, field_meta));
 							// TODO: detect when we can reverse this update, such as when shrinking an integer type
 						}
+						if old_column.optional != field_meta.optional {
+							up.push(self.set_column_nullability(table, column, field_meta.optional));
+							if let Some(down) = &mut down {
+								down.push(self.set_column_nullability(table, column, old_column.optional));
+							}
+						}
 						if old_column.identity_generation != field_meta.identity_generation {
 							if field_meta.identity_generation.is_some() {
-								up.push(self.add_identity_generation(table, &field_meta));
+								up.push(self.add_identity_generation(table, field_meta));
 							} else {
 								unimplemented!("removing identity generation is not supported yet {:?}", field_meta);
 							}
@@ -147,6 +364,45 @@ impl SqlGen for PostgresSqlGen {
 			}
 		}
 
+		// add foreign keys to existing tables, now that their columns exist
+		for (&table, &entity) in self.entities.into_iter().filter(|(&k, _)| old_schema.contains_key(k)) {
+			let old_foreign_keys = old_foreign_keys.get(table);
+			for foreign_key in entity.foreign_keys {
+				let name = Self::foreign_key_name(table, foreign_key.column);
+				if !old_foreign_keys.and_then(|x| x.get(&name)).is_some_and(|old| old == foreign_key) {
+					up.push(self.add_foreign_key(table, foreign_key.column, foreign_key.table, foreign_key.on_delete));
+					if let Some(down) = &mut down {
+						down.push(self.drop_constraint(table, &name));
+					}
+				}
+			}
+		}
+
+		// add unique constraints to existing tables
+		for (&table, &entity) in self.entities.into_iter().filter(|(&k, _)| old_schema.contains_key(k)) {
+			let old_unique = old_unique.get(table);
+			for &columns in entity.unique {
+				let name = Self::unique_name(table, columns);
+				if old_unique.and_then(|x| x.get(&name)).is_none_or(|old_columns| old_columns != columns) {
+					up.push(self.add_unique(table, columns));
+					if let Some(down) = &mut down {
+						down.push(self.drop_constraint(table, &name));
+					}
+				}
+			}
+		}
+
+		// drop enum types
+		for name in old_enums.keys().filter(|&k| !enums.contains_key(&**k)) {
+			up.push(self.drop_enum(name));
+			down = None;
+		}
+
+		// undo everything in reverse, so nothing is dropped while something else still depends on it
+		if let Some(down) = &mut down {
+			down.reverse();
+		}
+
 		Ok((up.join("\n"), down.map(|x| x.join("\n"))))
 	}
 
@@ -161,11 +417,17 @@ async fn get_old_table_info(pool: &Pool<Postgres>) -> Result<HashMap<String, Has
 		table_name: String,
 		column_name: String,
 		data_type: String,
+		is_nullable: bool,
 		identity_generation: Option<String>,
 	}
 	let fields_query = query_as::<_, FieldRow>(
-		"SELECT table_name, column_name, data_type, identity_generation
-		 identity_generation
+		"SELECT table_name, column_name,
+			CASE data_type
+				WHEN 'USER-DEFINED' THEN udt_name::text
+				WHEN 'ARRAY' THEN udt_name::regtype::text
+				ELSE data_type
+			END AS data_type,
+			is_nullable = 'YES' AS is_nullable, identity_generation
 		FROM information_schema.columns
 		WHERE table_schema = 'public' AND table_name <> '_sqlx_migrations';",
 	);
@@ -179,6 +441,7 @@ async fn get_old_table_info(pool: &Pool<Postgres>) -> Result<HashMap<String, Has
 				(x.column_name.clone(), PgField {
 					name: x.column_name,
 					ty: x.data_type,
+					optional: x.is_nullable,
 					identity_generation: x.identity_generation.map(|x| IdentityGeneration::from_name(&x)),
 				}),
 			)
@@ -201,9 +464,198 @@ async fn get_old_table_info(pool: &Pool<Postgres>) -> Result<HashMap<String, Has
 	Ok(fields)
 }
 
+/// Returns the values of each enum type, in order.
+async fn get_old_enum_info(pool: &Pool<Postgres>) -> Result<HashMap<String, Vec<String>>> {
+	#[derive(FromRow)]
+	struct EnumRow {
+		typname: String,
+		enumlabel: String,
+	}
+	let enums_query = query_as::<_, EnumRow>(
+		"SELECT t.typname, e.enumlabel
+		FROM pg_catalog.pg_enum e
+		JOIN pg_catalog.pg_type t ON t.oid = e.enumtypid
+		JOIN pg_catalog.pg_namespace n ON n.oid = t.typnamespace
+		WHERE n.nspname = 'public'
+		ORDER BY t.typname, e.enumsortorder;",
+	);
+	Ok(enums_query.fetch_all(pool).await?.into_iter().map(|x| (x.typname, x.enumlabel)).into_group_map())
+}
+
+/// Returns each table's single column foreign keys, by constraint name.
+async fn get_old_foreign_key_info(pool: &Pool<Postgres>) -> Result<HashMap<String, HashMap<String, PgForeignKey>>> {
+	#[derive(FromRow)]
+	struct ForeignKeyRow {
+		table_name: String,
+		constraint_name: String,
+		column_name: String,
+		foreign_table_name: String,
+		delete_rule: String,
+	}
+	let foreign_keys_query = query_as::<_, ForeignKeyRow>(
+		"SELECT tc.table_name, tc.constraint_name, kcu.column_name, ccu.table_name AS foreign_table_name,
+			rc.delete_rule
+		FROM information_schema.table_constraints tc
+		JOIN information_schema.key_column_usage kcu
+			ON kcu.constraint_schema = tc.constraint_schema AND kcu.constraint_name = tc.constraint_name
+		JOIN information_schema.referential_constraints rc
+			ON rc.constraint_schema = tc.constraint_schema AND rc.constraint_name = tc.constraint_name
+		JOIN information_schema.constraint_table_usage ccu
+			ON ccu.constraint_schema = tc.constraint_schema AND ccu.constraint_name = tc.constraint_name
+		WHERE tc.constraint_type = 'FOREIGN KEY' AND tc.table_schema = 'public';",
+	);
+	let foreign_keys = foreign_keys_query
+		.fetch_all(pool)
+		.await?
+		.into_iter()
+		.map(|x| {
+			(
+				x.table_name,
+				(x.constraint_name, PgForeignKey {
+					column: x.column_name,
+					table: x.foreign_table_name,
+					on_delete: OnDelete::from_name(&x.delete_rule),
+				}),
+			)
+		})
+		.into_group_map();
+	Ok(foreign_keys.into_iter().map(|(table, foreign_keys)| (table, foreign_keys.into_iter().collect())).collect())
+}
+
+/// Returns the columns of each table's unique constraints, by constraint name.
+async fn get_old_unique_info(pool: &Pool<Postgres>) -> Result<HashMap<String, HashMap<String, Vec<String>>>> {
+	#[derive(FromRow)]
+	struct UniqueRow {
+		table_name: String,
+		constraint_name: String,
+		column_name: String,
+	}
+	let unique_query = query_as::<_, UniqueRow>(
+		"SELECT tc.table_name, tc.constraint_name, kcu.column_name
+		FROM information_schema.table_constraints tc
+		JOIN information_schema.key_column_usage kcu
+			ON kcu.constraint_schema = tc.constraint_schema AND kcu.constraint_name = tc.constraint_name
+		WHERE tc.constraint_type = 'UNIQUE' AND tc.table_schema = 'public'
+		ORDER BY tc.table_name, tc.constraint_name, kcu.ordinal_position;",
+	);
+	let columns = unique_query
+		.fetch_all(pool)
+		.await?
+		.into_iter()
+		.map(|x| ((x.table_name, x.constraint_name), x.column_name))
+		.into_group_map();
+	let mut unique = HashMap::<_, HashMap<_, _>>::new();
+	for ((table, name), columns) in columns {
+		unique.entry(table).or_default().insert(name, columns);
+	}
+	Ok(unique)
+}
+
+struct PgForeignKey {
+	column: String,
+	table: String,
+	on_delete: OnDelete,
+}
+impl PartialEq<ForeignKey> for PgForeignKey {
+	fn eq(&self, other: &ForeignKey) -> bool {
+		self.column == other.column && self.table == other.table && self.on_delete == other.on_delete
+	}
+}
+
 struct PgField {
 	#[allow(unused)]
 	name: String,
 	ty: String,
+	optional: bool,
 	identity_generation: Option<IdentityGeneration>,
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use nice_orm::{entity, phf, DbEnum, EntityExt};
+
+	entity!(Writer {
+		#[entity_field(primary_key)]
+		id: i64,
+		#[entity_field(references = "Book")]
+		best_book_id: Option<i64>,
+		genre: Genre,
+	}
+	Book {
+		#[entity_field(primary_key)]
+		id: i64,
+		#[entity_field(references = "Writer", on_delete = "cascade")]
+		writer_id: i64,
+		#[entity_field(references = "Book")]
+		sequel_of: Option<i64>,
+	});
+
+	#[derive(Debug, Clone, PartialEq, DbEnum)]
+	pub enum Genre {
+		Poetry,
+		ScienceFiction,
+	}
+
+	// the pool is never used, since these tests don't diff against a database
+	fn sql_gen() -> PostgresSqlGen {
+		PostgresSqlGen { entities: &ENTITIES, pool: PgPoolOptions::new().connect_lazy("postgres://localhost").unwrap() }
+	}
+
+	#[tokio::test]
+	async fn enum_statements() {
+		let sql_gen = sql_gen();
+		assert_eq!(sql_gen.enum_types().into_iter().collect::<Vec<_>>(), vec![(
+			"genre",
+			&["poetry", "science_fiction"][..]
+		)]);
+		assert_eq!(
+			sql_gen.create_enum("genre", &["poetry", "science_fiction"]),
+			"CREATE TYPE \"genre\" AS ENUM ('poetry', 'science_fiction');"
+		);
+		assert_eq!(
+			sql_gen.add_enum_value("genre", "drama", " AFTER 'poetry'"),
+			"ALTER TYPE \"genre\" ADD VALUE 'drama' AFTER 'poetry';"
+		);
+	}
+
+	#[tokio::test]
+	async fn cycle_defers_one_foreign_key() {
+		let sql_gen = sql_gen();
+		let order = sql_gen.creation_order(&["writer", "book"]);
+		assert_eq!(order, vec!["book", "writer"]);
+
+		// book is created first, so only its reference to itself can be inline
+		let (deferred, inline) = sql_gen.split_foreign_keys(&order, 0);
+		assert_eq!(deferred.len(), 1);
+		let create = sql_gen.create_table(Book::META, &inline);
+		assert!(create.contains(
+			"CONSTRAINT \"book_sequel_of_fkey\" FOREIGN KEY (\"sequel_of\") REFERENCES \"book\" ON DELETE NO ACTION"
+		));
+		assert!(!create.contains("book_writer_id_fkey"));
+		assert_eq!(
+			sql_gen.add_foreign_key("book", deferred[0].column, deferred[0].table, deferred[0].on_delete),
+			"ALTER TABLE \"book\" ADD CONSTRAINT \"book_writer_id_fkey\" FOREIGN KEY (\"writer_id\") REFERENCES \
+			 \"writer\" ON DELETE CASCADE;"
+		);
+		assert_eq!(
+			sql_gen.drop_constraint("book", "book_writer_id_fkey"),
+			"ALTER TABLE \"book\" DROP CONSTRAINT \"book_writer_id_fkey\";"
+		);
+
+		let (deferred, inline) = sql_gen.split_foreign_keys(&order, 1);
+		assert!(deferred.is_empty());
+		let create = sql_gen.create_table(Writer::META, &inline);
+		assert!(create.contains(
+			"CONSTRAINT \"writer_best_book_id_fkey\" FOREIGN KEY (\"best_book_id\") REFERENCES \"book\" ON DELETE NO \
+			 ACTION"
+		));
+	}
+
+	#[tokio::test]
+	async fn referenced_tables_come_first() {
+		let sql_gen = sql_gen();
+		assert_eq!(sql_gen.creation_order(&["book"]), vec!["book"]);
+		assert_eq!(sql_gen.creation_order(&["book", "writer"]), vec!["writer", "book"]);
+	}
+}
@@ -1,19 +1,24 @@
+// the code darling generates for `#[darling(default)]` trips this lint
+#![allow(clippy::manual_unwrap_or_default)]
+
 extern crate proc_macro;
 
 use convert_case::{Case, Casing};
-use darling::FromField;
+use darling::{FromField, FromMeta};
 use proc_macro::TokenStream;
-use proc_macro2::Span;
+use proc_macro2::{Span, TokenStream as TokenStream2};
 use proc_macro_crate::{crate_name, FoundCrate};
 use proc_macro_error::{abort, proc_macro_error};
-use quote::{quote, ToTokens};
+use quote::{quote, quote_spanned, ToTokens};
+use std::collections::HashMap;
 use syn::{
 	braced,
 	parse::{Nothing, Parse, ParseStream},
 	parse_macro_input,
 	punctuated::Punctuated,
+	spanned::Spanned,
 	token::Brace,
-	Field, Ident, Result, Token, Type,
+	Attribute, Data, DeriveInput, Field, Fields, GenericArgument, Ident, PathArguments, Result, Token, Type, TypePath,
 };
 
 #[proc_macro]
@@ -21,12 +26,27 @@ use syn::{
 pub fn entity(input: TokenStream) -> TokenStream {
 	let nice_orm = find_crate("nice-orm");
 
-	let entities = parse_macro_input!(input as Entities);
+	let mut entities = parse_macro_input!(input as Entities).0.into_iter().collect::<Vec<_>>();
+	let many_to_many = resolve_many_to_many(&mut entities);
+
+	// has_many relations are declared on the child's foreign key, but generate items for the parent
+	let mut has_many = HashMap::<String, Vec<(&Ident, &EntityField, &String)>>::new();
+	for entity in &entities {
+		for field in &entity.fields {
+			if let Some(name) = &field.has_many {
+				let parent = field.referenced_entity().unwrap();
+				if !entities.iter().any(|x| x.ident == parent) {
+					abort!(field.ident, "has_many requires {} to be declared in the same entity! invocation", parent);
+				}
+				has_many.entry(parent.to_string()).or_default().push((&entity.ident, field, name));
+			}
+		}
+	}
 
 	let mut outputs = vec![];
 	let mut metas = vec![];
 
-	for entity in entities.0 {
+	for entity in &entities {
 		let fields = entity.fields.iter().map(|field| {
 			let ident = &field.ident;
 			let ty = &field.ty;
@@ -37,10 +57,13 @@ pub fn entity(input: TokenStream) -> TokenStream {
 			let ty = &field.ty;
 			let getter_name = syn::Ident::new(&format!("{}", ident), Span::call_site());
 			let setter_name = syn::Ident::new(&format!("set_{}", ident), Span::call_site());
-			let getter = quote! { pub fn #getter_name(&self) -> &#ty { &self.#ident.get() } };
+			let getter = match option_inner(ty) {
+				Some(inner) => quote! { pub fn #getter_name(&self) -> Option<&#inner> { self.#ident.get().as_ref() } },
+				None => quote! { pub fn #getter_name(&self) -> &#ty { &self.#ident.get() } },
+			};
 			let setter =
 				quote! { pub fn #setter_name(&mut self, value: #ty) -> &mut Self { self.#ident = #nice_orm::EntityField::Modified(value); self } };
-			if field.identity_generation.as_ref().map(|x| &**x) == Some("always") {
+			if field.identity_generation.as_deref() == Some("always") {
 				quote! { #getter }
 			} else {
 				quote! { #getter #setter }
@@ -48,22 +71,23 @@ pub fn entity(input: TokenStream) -> TokenStream {
 		});
 		let field_inits = entity.fields.iter().map(|field| {
 			let ident = &field.ident;
-			quote! { #ident: #nice_orm::EntityField::Unset }
+			let ty = &field.ty;
+			if field.version {
+				quote_spanned! {ty.span()=>
+					#ident: #nice_orm::EntityField::Modified(<#ty as #nice_orm::entity_meta::VersionType>::initial())
+				}
+			} else {
+				quote! { #ident: #nice_orm::EntityField::Unset }
+			}
 		});
 		let field_metas = entity
 			.fields
 			.iter()
 			.map(|field| {
 				let field_name = field.ident.as_ref().unwrap().to_string();
-				let type_i32: Type = syn::parse_str("i32").unwrap();
-				let type_string: Type = syn::parse_str("String").unwrap();
-				let ty = if field.ty == type_i32 {
-					quote! { #nice_orm::entity_meta::FieldType::I32 }
-				} else if field.ty == type_string {
-					quote! { #nice_orm::entity_meta::FieldType::String }
-				} else {
-					unreachable!()
-				};
+				let field_ty = &field.ty;
+				let ty = quote_spanned! {field_ty.span()=> &#nice_orm::entity_meta::FieldTypeOf::<#field_ty>::NEW };
+				let optional = quote! { <#field_ty as #nice_orm::entity_meta::FieldType>::NULLABLE };
 				let identity_generation = if let Some(identity_generation) = &field.identity_generation {
 					if identity_generation == "always" {
 						quote! { Some(#nice_orm::entity_meta::IdentityGeneration::Always) }
@@ -75,15 +99,45 @@ pub fn entity(input: TokenStream) -> TokenStream {
 				} else {
 					quote! { None }
 				};
-				quote! { #field_name => #nice_orm::entity_meta::FieldMeta { name: #field_name, ty: #ty, optional: false, identity_generation: #identity_generation } }
+				quote! { #field_name => #nice_orm::entity_meta::FieldMeta { name: #field_name, ty: #ty, optional: #optional, identity_generation: #identity_generation } }
 			})
 			.collect::<Vec<_>>();
+		let foreign_keys = entity
+			.fields
+			.iter()
+			.filter_map(|field| {
+				let column = field.ident.as_ref().unwrap().to_string();
+				let table = field.referenced_table()?;
+				let on_delete = field.on_delete.as_deref().unwrap_or("no_action").to_case(Case::Pascal);
+				let on_delete = syn::Ident::new(&on_delete, Span::call_site());
+				Some(quote! {
+					#nice_orm::entity_meta::ForeignKey {
+						column: #column,
+						table: #table,
+						on_delete: #nice_orm::entity_meta::OnDelete::#on_delete,
+					}
+				})
+			})
+			.collect::<Vec<_>>();
+		let version = match entity.fields.iter().find(|field| field.version) {
+			Some(field) => {
+				let column = field.ident.as_ref().unwrap().to_string();
+				let ty = &field.ty;
+				quote_spanned! {ty.span()=>
+					Some(#nice_orm::entity_meta::Version {
+						column: #column,
+						next: <#ty as #nice_orm::entity_meta::VersionType>::next,
+					})
+				}
+			},
+			None => quote! { None },
+		};
 		let primary_key_idents =
 			entity.fields.iter().filter(|field| field.primary_key).map(|field| field.ident.clone()).collect::<Vec<_>>();
 		let primary_key =
 			primary_key_idents.iter().map(|ident| ident.as_ref().unwrap().to_string()).collect::<Vec<_>>();
 
-		let ident = entity.ident;
+		let ident = &entity.ident;
 		let table_name = ident.to_string().to_case(Case::Snake);
 
 		let mod_ident = syn::Ident::new(&table_name, Span::call_site());
@@ -99,18 +153,133 @@ pub fn entity(input: TokenStream) -> TokenStream {
 			})
 			.collect::<Vec<_>>();
 
+		let mut relation_exprs = vec![];
+		let mut relation_accessors = vec![];
+		for field in &entity.fields {
+			let name = match &field.belongs_to {
+				Some(name) => syn::Ident::new(name, Span::call_site()),
+				None => continue,
+			};
+			let name_str = name.to_string();
+			let load = syn::Ident::new(&format!("__load_{}", name), Span::call_site());
+			let parent: syn::Path = syn::parse_str(field.references.as_ref().unwrap()).unwrap();
+			let key = field.key_value(quote! { self });
+			let entity_key = field.key_value(quote! { entity });
+			relation_exprs.push(quote! {
+				#[allow(non_upper_case_globals)]
+				pub const #name: #nice_orm::relation::Relation<super::#ident> =
+					#nice_orm::relation::Relation { name: #name_str, load: super::#ident::#load };
+			});
+			relation_accessors.push(quote! {
+				pub async fn #name(
+					&self,
+					ctx: &mut #nice_orm::entity_manager::DbContext,
+				) -> #nice_orm::anyhow::Result<Option<std::sync::Arc<#nice_orm::tokio::sync::RwLock<#parent>>>> {
+					match #key {
+						Some(key) => ctx.find::<#parent, _>(key).await,
+						None => Ok(None),
+					}
+				}
+
+				fn #load<'a>(
+					ctx: &'a mut #nice_orm::entity_manager::DbContext,
+					parents: &'a [std::sync::Arc<#nice_orm::tokio::sync::RwLock<Self>>],
+				) -> #nice_orm::relation::RelationFuture<'a> {
+					Box::pin(ctx.load_belongs_to::<Self, #parent, _>(parents, |entity: &Self| #entity_key))
+				}
+			});
+		}
+		for (child, field, name) in has_many.get(&ident.to_string()).into_iter().flatten() {
+			let primary_key = match &*primary_key_idents {
+				[primary_key] => primary_key.as_ref().unwrap(),
+				_ => abort!(ident, "{} needs a single primary key field to be the target of has_many", ident),
+			};
+			let name_str = name.to_string();
+			let name = syn::Ident::new(name, Span::call_site());
+			let load = syn::Ident::new(&format!("__load_{}", name), Span::call_site());
+			let child_mod = syn::Ident::new(&child.to_string().to_case(Case::Snake), Span::call_site());
+			let foreign_key = field.ident.as_ref().unwrap();
+			let child_key = field.key_value(quote! { child });
+			relation_exprs.push(quote! {
+				#[allow(non_upper_case_globals)]
+				pub const #name: #nice_orm::relation::Relation<super::#ident> =
+					#nice_orm::relation::Relation { name: #name_str, load: super::#ident::#load };
+			});
+			relation_accessors.push(quote! {
+				pub async fn #name(
+					&self,
+					ctx: &mut #nice_orm::entity_manager::DbContext,
+				) -> #nice_orm::anyhow::Result<Vec<std::sync::Arc<#nice_orm::tokio::sync::RwLock<#child>>>> {
+					let key = self.#primary_key().clone();
+					ctx.has_many::<Self, #child, _>(#name_str, key, #child_mod::#foreign_key).await
+				}
+
+				fn #load<'a>(
+					ctx: &'a mut #nice_orm::entity_manager::DbContext,
+					parents: &'a [std::sync::Arc<#nice_orm::tokio::sync::RwLock<Self>>],
+				) -> #nice_orm::relation::RelationFuture<'a> {
+					Box::pin(ctx.load_has_many::<Self, #child, _>(
+						#name_str,
+						parents,
+						#child_mod::#foreign_key,
+						|parent: &Self| parent.#primary_key().clone(),
+						|child: &#child| #child_key,
+					))
+				}
+			});
+		}
+
+		for link in many_to_many.iter().filter(|link| link.owner == *ident) {
+			let ManyToManyLink { other, join, owner_key, other_key, owner_column, other_column, .. } = link;
+			let name = &link.name;
+			let name_str = name.to_string();
+			let join_fn = syn::Ident::new(&format!("__join_{}", name), Span::call_site());
+			let join_mod = syn::Ident::new(&join.to_string().to_case(Case::Snake), Span::call_site());
+			let set_owner_column = syn::Ident::new(&format!("set_{}", owner_column), Span::call_site());
+			let set_other_column = syn::Ident::new(&format!("set_{}", other_column), Span::call_site());
+			relation_exprs.push(quote! {
+				#[allow(non_upper_case_globals)]
+				pub const #name: #nice_orm::relation::ManyToMany<super::#ident, super::#other> =
+					#nice_orm::relation::ManyToMany { name: #name_str, join: super::#ident::#join_fn };
+			});
+			relation_accessors.push(quote! {
+				pub async fn #name(
+					&self,
+					ctx: &mut #nice_orm::entity_manager::DbContext,
+				) -> #nice_orm::anyhow::Result<Vec<std::sync::Arc<#nice_orm::tokio::sync::RwLock<#other>>>> {
+					let key = self.#owner_key().clone();
+					ctx.many_to_many::<#other, #join, _>(key, #join_mod::#owner_column, #join_mod::#other_column).await
+				}
+
+				fn #join_fn(entity: &Self, other: &#other) -> Box<dyn #nice_orm::Entity> {
+					let mut join = <#join as #nice_orm::EntityExt>::new();
+					join.#set_owner_column(entity.#owner_key().clone()).#set_other_column(other.#other_key().clone());
+					Box::new(join)
+				}
+			});
+		}
+
+		let unique = entity
+			.unique
+			.iter()
+			.map(|columns| columns.iter().map(|column| column.to_string()).collect::<Vec<_>>())
+			.collect::<Vec<_>>();
+
 		let meta = quote! {
 			&#nice_orm::entity_meta::EntityMeta {
 				table_name: #table_name,
 				fields: #nice_orm::phf::phf_map! { #(#field_metas),* },
 				primary_key: &[#(#primary_key),*],
+				foreign_keys: &[#(#foreign_keys),*],
+				unique: &[#(&[#(#unique),*]),*],
+				version: #version,
 			}
 		};
-		let meta_clone = meta.clone();
 
 		outputs.push(quote! {
-			mod #mod_ident {
+			pub mod #mod_ident {
 				#(#field_exprs)*
+				#(#relation_exprs)*
 			}
 
 			#[derive(#nice_orm::bevy_reflect::Reflect)]
@@ -119,10 +288,11 @@ pub fn entity(input: TokenStream) -> TokenStream {
 			}
 			impl #ident {
 				#(#field_accessors)*
+				#(#relation_accessors)*
 			}
 			impl #nice_orm::Entity for #ident {
 				fn meta(&self) -> &'static #nice_orm::entity_meta::EntityMeta {
-					#meta
+					<Self as #nice_orm::EntityExt>::META
 				}
 
 				fn id(&self) -> Box<dyn #nice_orm::Key + Send + Sync> {
@@ -130,7 +300,7 @@ pub fn entity(input: TokenStream) -> TokenStream {
 				}
 			}
 			impl #nice_orm::EntityExt for #ident {
-				const META: &'static #nice_orm::entity_meta::EntityMeta = #meta_clone;
+				const META: &'static #nice_orm::entity_meta::EntityMeta = #meta;
 
 				fn new() -> Self {
 					Self { #(#field_inits),* }
@@ -148,6 +318,7 @@ pub fn entity(input: TokenStream) -> TokenStream {
 		}
 
 		mod __entities {
+			use super::*;
 			use #nice_orm::bevy_reflect::{self, Reflect};
 			use #nice_orm::phf;
 
@@ -158,6 +329,101 @@ pub fn entity(input: TokenStream) -> TokenStream {
 	.into()
 }
 
+/// Maps a fieldless enum to a Postgres enum type, named after the enum in snake case. Each variant is stored as its
+/// name in snake case.
+#[proc_macro_derive(DbEnum)]
+#[proc_macro_error]
+pub fn db_enum(input: TokenStream) -> TokenStream {
+	let nice_orm = find_crate("nice-orm");
+	let sqlx = quote! { #nice_orm::sqlx };
+
+	let input = parse_macro_input!(input as DeriveInput);
+	let variants = match &input.data {
+		Data::Enum(data) => &data.variants,
+		_ => abort!(input.ident, "DbEnum can only be derived for enums"),
+	};
+	for variant in variants {
+		if !matches!(variant.fields, Fields::Unit) {
+			abort!(variant.fields, "DbEnum variants can't have fields");
+		}
+	}
+
+	let ident = &input.ident;
+	let type_name = ident.to_string().to_case(Case::Snake);
+	let variant_idents = variants.iter().map(|variant| &variant.ident).collect::<Vec<_>>();
+	let labels = variants.iter().map(|variant| variant.ident.to_string().to_case(Case::Snake)).collect::<Vec<_>>();
+	let array_column_type = format!("{}[]", type_name);
+	let array_type_name = format!("_{}", type_name);
+
+	quote! {
+		impl #nice_orm::entity_meta::FieldType for #ident {
+			const COLUMN_TYPE: &'static str = #type_name;
+			const ENUM_VALUES: Option<&'static [&'static str]> = Some(&[#(#labels),*]);
+		}
+
+		impl #nice_orm::entity_meta::ArrayElement for #ident {
+			const ARRAY_COLUMN_TYPE: &'static str = #array_column_type;
+		}
+
+		impl #sqlx::Type<#sqlx::Postgres> for #ident {
+			fn type_info() -> #sqlx::postgres::PgTypeInfo {
+				#sqlx::postgres::PgTypeInfo::with_name(#type_name)
+			}
+		}
+
+		impl #sqlx::postgres::PgHasArrayType for #ident {
+			fn array_type_info() -> #sqlx::postgres::PgTypeInfo {
+				#sqlx::postgres::PgTypeInfo::with_name(#array_type_name)
+			}
+		}
+
+		impl<'q> #sqlx::Encode<'q, #sqlx::Postgres> for #ident {
+			fn encode_by_ref(&self, buf: &mut #sqlx::postgres::PgArgumentBuffer) -> #sqlx::encode::IsNull {
+				let label = match self {
+					#(Self::#variant_idents => #labels),*
+				};
+				<&str as #sqlx::Encode<'q, #sqlx::Postgres>>::encode(label, buf)
+			}
+		}
+
+		impl<'r> #sqlx::Decode<'r, #sqlx::Postgres> for #ident {
+			fn decode(value: #sqlx::postgres::PgValueRef<'r>) -> Result<Self, #sqlx::error::BoxDynError> {
+				match <&str as #sqlx::Decode<'r, #sqlx::Postgres>>::decode(value)? {
+					#(#labels => Ok(Self::#variant_idents),)*
+					label => Err(format!("Unknown {} value: {}", #type_name, label).into()),
+				}
+			}
+		}
+
+		impl #nice_orm::query::Expression for #ident {
+			fn push_to(&self, query: &mut #nice_orm::query::SqlBuilder) {
+				query.push_param(<Self as #sqlx::Type<#sqlx::Postgres>>::type_info());
+			}
+
+			fn bind_to<'a>(
+				&'a self,
+				query: #sqlx::query::Query<'a, #sqlx::Postgres, #sqlx::postgres::PgArguments>,
+			) -> #sqlx::query::Query<'a, #sqlx::Postgres, #sqlx::postgres::PgArguments> {
+				query.bind(self).persistent(true)
+			}
+
+			fn write_values(&self, out: &mut Vec<u8>) -> bool {
+				#nice_orm::query::write_value(self, out)
+			}
+		}
+
+		impl #nice_orm::middleware::FromAggregateValue for #ident {
+			fn from_aggregate_value(value: #nice_orm::middleware::AggregateValue) -> #nice_orm::anyhow::Result<Self> {
+				match <String as #nice_orm::middleware::FromAggregateValue>::from_aggregate_value(value)?.as_str() {
+					#(#labels => Ok(Self::#variant_idents),)*
+					label => Err(#nice_orm::anyhow::anyhow!("Unknown {} value: {}", #type_name, label)),
+				}
+			}
+		}
+	}
+	.into()
+}
+
 struct Entities(Punctuated<Entity, Nothing>);
 impl Parse for Entities {
 	fn parse(input: ParseStream) -> Result<Self> {
@@ -166,40 +432,326 @@ impl Parse for Entities {
 }
 
 struct Entity {
+	many_to_many: Vec<ManyToMany>,
+	unique: Vec<Vec<Ident>>,
 	ident: Ident,
 	_brace_token: Brace,
 	fields: Vec<EntityField>,
 }
+impl Entity {
+	fn find<'a>(entities: &'a [Entity], name: &str) -> Option<&'a Entity> {
+		let name = name.rsplit("::").next().unwrap().trim();
+		entities.iter().find(|entity| entity.ident == name)
+	}
+
+	/// The entity's primary key field, which relations to it need to be the only one.
+	fn single_primary_key(&self, relation: &str) -> &EntityField {
+		let mut primary_key = self.fields.iter().filter(|field| field.primary_key);
+		match (primary_key.next(), primary_key.next()) {
+			(Some(field), None) => field,
+			_ => abort!(self.ident, "{} needs a single primary key field to be the target of {}", self.ident, relation),
+		}
+	}
+}
 impl Parse for Entity {
 	fn parse(input: ParseStream) -> Result<Self> {
 		let content;
 
+		let mut many_to_many = vec![];
+		let mut unique = vec![];
+		for attr in input.call(Attribute::parse_outer)? {
+			if attr.path.is_ident("many_to_many") {
+				let relation = ManyToMany::from_meta(&attr.parse_meta()?)?;
+				for name in [Some(&relation.name), relation.inverse.as_ref()].into_iter().flatten() {
+					syn::parse_str::<Ident>(name)
+						.map_err(|_| syn::Error::new_spanned(&attr, format!("{} isn't a valid identifier", name)))?;
+				}
+				many_to_many.push(relation);
+			} else if attr.path.is_ident("unique") {
+				let columns = attr.parse_args_with(Punctuated::<Ident, Token![,]>::parse_separated_nonempty)?;
+				unique.push(columns.into_iter().collect::<Vec<_>>());
+			} else {
+				return Err(syn::Error::new_spanned(attr, "expected #[many_to_many(...)] or #[unique(...)]"));
+			}
+		}
 		let ident = input.parse()?;
 		let _brace_token = braced!(content in input);
 		let fields = content.parse_terminated::<_, Token![,]>(Field::parse_named)?;
-		let fields = fields.into_iter().map(|field| EntityField::from_field(&field).unwrap()).collect();
+		let fields = fields
+			.into_iter()
+			.map(|field| {
+				let field = EntityField::from_field(&field)?;
+				field.validate_type()?;
+				field.validate_references()?;
+				field.validate_version()?;
+				Ok(field)
+			})
+			.collect::<Result<Vec<_>>>()?;
+		if let Some(field) = fields.iter().filter(|field| field.version).nth(1) {
+			return Err(syn::Error::new_spanned(&field.ident, format!("{} already has a version field", ident)));
+		}
+		for column in unique.iter().flatten() {
+			if !fields.iter().any(|field| field.ident.as_ref() == Some(column)) {
+				return Err(syn::Error::new_spanned(column, format!("{} has no field named {}", ident, column)));
+			}
+		}
 
-		Ok(Entity { ident, _brace_token, fields })
+		Ok(Entity { many_to_many, unique, ident, _brace_token, fields })
 	}
 }
 
+/// A `#[many_to_many(...)]` attribute on an entity.
+#[derive(FromMeta)]
+struct ManyToMany {
+	/// The name of the accessor generated on this entity.
+	name: String,
+	/// The entity on the other side, which must be declared in the same `entity!` invocation.
+	entity: String,
+	/// The name of the accessor generated on the other entity, if any.
+	#[darling(default)]
+	inverse: Option<String>,
+	/// The join entity, whose primary key must be a foreign key to each side. If omitted, one named after both
+	/// entities is generated.
+	#[darling(default)]
+	through: Option<String>,
+}
+
+/// One direction of a many-to-many relation, from `owner` to `other` through `join`.
+struct ManyToManyLink {
+	name: Ident,
+	owner: Ident,
+	other: Ident,
+	join: Ident,
+	owner_key: Ident,
+	other_key: Ident,
+	owner_column: Ident,
+	other_column: Ident,
+}
+
+/// Finds the join entity of every `many_to_many` attribute, adding the ones that need to be generated to `entities`.
+fn resolve_many_to_many(entities: &mut Vec<Entity>) -> Vec<ManyToManyLink> {
+	let mut links = vec![];
+	let mut join_entities = vec![];
+	for entity in entities.iter() {
+		for relation in &entity.many_to_many {
+			let other = Entity::find(entities, &relation.entity).unwrap_or_else(|| {
+				abort!(entity.ident, "{} must be declared in the same entity! invocation", relation.entity)
+			});
+			let owner_key = entity.single_primary_key("many_to_many").ident.clone().unwrap();
+			let other_key = other.single_primary_key("many_to_many").ident.clone().unwrap();
+
+			let (join, owner_column, other_column) = match &relation.through {
+				Some(through) => {
+					let join = Entity::find(entities, through).unwrap_or_else(|| {
+						abort!(entity.ident, "{} must be declared in the same entity! invocation", through)
+					});
+					let references = |field: &&EntityField, target: &Entity| {
+						field.referenced_entity().is_some_and(|name| target.ident == name)
+					};
+					let owner_column = join.fields.iter().find(|field| references(field, entity));
+					let other_column = join.fields.iter().find(|field| {
+						references(field, other) && owner_column.is_none_or(|owner| field.ident != owner.ident)
+					});
+					let primary_key = join.fields.iter().filter(|field| field.primary_key).collect::<Vec<_>>();
+					match (owner_column, other_column) {
+						(Some(owner_column), Some(other_column))
+							if primary_key.len() == 2 && owner_column.primary_key && other_column.primary_key =>
+						{
+							(
+								join.ident.clone(),
+								owner_column.ident.clone().unwrap(),
+								other_column.ident.clone().unwrap(),
+							)
+						},
+						_ => abort!(
+							join.ident,
+							"{}'s primary key must be a foreign key to {} and one to {}",
+							join.ident,
+							entity.ident,
+							other.ident
+						),
+					}
+				},
+				None => {
+					if entity.ident == other.ident {
+						abort!(
+							entity.ident,
+							"a many_to_many of an entity with itself needs an explicit through entity"
+						);
+					}
+					let join = syn::Ident::new(&format!("{}{}", entity.ident, other.ident), Span::call_site());
+					let owner_column = join_column(entity, &owner_key);
+					let other_column = join_column(other, &other_key);
+					let join_field = |column: &Ident, target: &Entity, key: &Ident| EntityField {
+						ident: Some(column.clone()),
+						ty: target.fields.iter().find(|field| field.ident.as_ref() == Some(key)).unwrap().ty.clone(),
+						primary_key: true,
+						identity_generation: None,
+						references: Some(target.ident.to_string()),
+						on_delete: Some("cascade".into()),
+						belongs_to: None,
+						has_many: None,
+						version: false,
+					};
+					join_entities.push(Entity {
+						many_to_many: vec![],
+						unique: vec![],
+						ident: join.clone(),
+						_brace_token: Brace::default(),
+						fields: vec![
+							join_field(&owner_column, entity, &owner_key),
+							join_field(&other_column, other, &other_key),
+						],
+					});
+					(join, owner_column, other_column)
+				},
+			};
+
+			if let Some(inverse) = &relation.inverse {
+				links.push(ManyToManyLink {
+					name: syn::Ident::new(inverse, Span::call_site()),
+					owner: other.ident.clone(),
+					other: entity.ident.clone(),
+					join: join.clone(),
+					owner_key: other_key.clone(),
+					other_key: owner_key.clone(),
+					owner_column: other_column.clone(),
+					other_column: owner_column.clone(),
+				});
+			}
+			links.push(ManyToManyLink {
+				name: syn::Ident::new(&relation.name, Span::call_site()),
+				owner: entity.ident.clone(),
+				other: other.ident.clone(),
+				join,
+				owner_key,
+				other_key,
+				owner_column,
+				other_column,
+			});
+		}
+	}
+	entities.extend(join_entities);
+	links
+}
+
+/// The column a generated join entity references `entity` with, such as `account_id`.
+fn join_column(entity: &Entity, key: &Ident) -> Ident {
+	syn::Ident::new(&format!("{}_{}", entity.ident.to_string().to_case(Case::Snake), key), Span::call_site())
+}
+
 #[derive(FromField)]
 #[darling(attributes(entity_field))]
 struct EntityField {
 	ident: Option<Ident>,
-	#[darling(and_then = "EntityField::validate_type")]
 	ty: Type,
 	#[darling(default)]
 	primary_key: bool,
 	#[darling(default)]
 	identity_generation: Option<String>,
+	/// The name of the entity this field holds the primary key of.
+	#[darling(default)]
+	references: Option<String>,
+	#[darling(default)]
+	on_delete: Option<String>,
+	/// Generates an accessor with this name on this entity, returning the referenced entity.
+	#[darling(default)]
+	belongs_to: Option<String>,
+	/// Generates an accessor with this name on the referenced entity, returning every entity that references it.
+	#[darling(default)]
+	has_many: Option<String>,
+	/// Checks and increments this field on every update, so concurrent updates of the same row conflict instead of
+	/// overwriting each other.
+	#[darling(default)]
+	version: bool,
 }
 impl EntityField {
-	// used by darling above
-	#[allow(unused)]
-	fn validate_type(ty: Type) -> darling::Result<Type> {
-		let type_i32: Type = syn::parse_str("i32").unwrap();
-		if ty == type_i32 { Ok(ty) } else { Err(darling::Error::custom("unsupported type")) }
+	fn validate_type(&self) -> Result<()> {
+		// whether the type is supported at all is checked by the compiler, through the `FieldType` trait
+		if !self.primary_key {
+			Ok(())
+		} else if option_inner(&self.ty).is_some() {
+			Err(syn::Error::new_spanned(&self.ty, "primary key fields can't be optional"))
+		} else if ["f32", "f64"].iter().any(|name| self.ty == syn::parse_str::<Type>(name).unwrap()) {
+			Err(syn::Error::new_spanned(&self.ty, "primary key fields can't be floating point"))
+		} else {
+			Ok(())
+		}
+	}
+
+	fn validate_references(&self) -> Result<()> {
+		const ON_DELETE: &[&str] = &["no_action", "restrict", "cascade", "set_null", "set_default"];
+		if let Some(references) = &self.references {
+			syn::parse_str::<syn::Path>(references)
+				.map_err(|_| syn::Error::new_spanned(&self.ident, "references must be the path of an entity"))?;
+		}
+		for relation in [&self.belongs_to, &self.has_many].into_iter().flatten() {
+			if self.references.is_none() {
+				return Err(syn::Error::new_spanned(&self.ident, "belongs_to and has_many require references"));
+			}
+			syn::parse_str::<Ident>(relation)
+				.map_err(|_| syn::Error::new_spanned(&self.ident, format!("{} isn't a valid identifier", relation)))?;
+		}
+		match &self.on_delete {
+			Some(_) if self.references.is_none() => {
+				Err(syn::Error::new_spanned(&self.ident, "on_delete requires references"))
+			},
+			Some(on_delete) if !ON_DELETE.contains(&&**on_delete) => {
+				Err(syn::Error::new_spanned(&self.ident, format!("on_delete must be one of {}", ON_DELETE.join(", "))))
+			},
+			_ => Ok(()),
+		}
+	}
+
+	fn validate_version(&self) -> Result<()> {
+		// whether the type can be a version is checked by the compiler, through the `VersionType` trait
+		if self.version && (self.primary_key || self.identity_generation.is_some() || self.references.is_some()) {
+			Err(syn::Error::new_spanned(
+				&self.ident,
+				"version fields can't be primary keys, identity columns or references",
+			))
+		} else {
+			Ok(())
+		}
+	}
+
+	/// The name of the entity this field references, without its module path.
+	fn referenced_entity(&self) -> Option<&str> {
+		Some(self.references.as_ref()?.rsplit("::").next().unwrap().trim())
+	}
+
+	/// The table of the entity this field references.
+	fn referenced_table(&self) -> Option<String> {
+		Some(self.referenced_entity()?.to_case(Case::Snake))
+	}
+
+	/// An expression for this field's value on `receiver` as an `Option`, which is `None` only for unset optional
+	/// fields.
+	fn key_value(&self, receiver: TokenStream2) -> TokenStream2 {
+		let ident = self.ident.as_ref().unwrap();
+		match option_inner(&self.ty) {
+			Some(_) => quote! { #receiver.#ident().cloned() },
+			None => quote! { Some(#receiver.#ident().clone()) },
+		}
+	}
+}
+
+/// Returns `T` if `ty` is `Option<T>`.
+fn option_inner(ty: &Type) -> Option<&Type> {
+	let path = match ty {
+		Type::Path(TypePath { qself: None, path }) => path,
+		_ => return None,
+	};
+	let segment = path.segments.last()?;
+	if segment.ident != "Option" {
+		return None;
+	}
+	match &segment.arguments {
+		PathArguments::AngleBracketed(args) if args.args.len() == 1 => match args.args.first()? {
+			GenericArgument::Type(ty) => Some(ty),
+			_ => None,
+		},
+		_ => None,
 	}
 }
 
@@ -1,14 +1,41 @@
 use crate::{
-	entity_meta::FieldType,
-	middleware::{AggregateNext, EventListener, FlushNext},
-	query::{Predicate, SqlBuilder},
-	Entity, EntityExt, EntityField, Key,
+	entity_meta::{EntityMeta, FieldMeta},
+	middleware::{
+		AggregateNext, AggregateValue, BulkDeleteNext, BulkUpdateNext, EventListener, FlushManyNext, FlushNext,
+		FromAggregateValue, RemoveNext, UpdateNext,
+	},
+	query::{
+		Expression, ExpressionExt, Field, Lock, LockStrength, LockWait, OrderBy, Predicate, PredicateExt, PrimaryKey,
+		SelectClauses, SqlBuilder, UpdateSet,
+	},
+	relation::{ManyToMany, Relation},
+	Entity, EntityExt, Key,
+};
+use anyhow::{bail, Result};
+use bevy_reflect::DynamicStruct;
+use futures::{future::BoxFuture, FutureExt};
+use sqlx::{
+	postgres::{PgArguments, PgPoolOptions, PgRow, PgTransactionManager},
+	query,
+	query::Query,
+	PgPool, Postgres, Row, Transaction, TransactionManager, ValueRef,
+};
+use std::{
+	any::{Any, TypeId},
+	collections::{HashMap, HashSet},
+	error::Error,
+	fmt::{self, Debug, Write},
+	hash::Hash,
+	marker::PhantomData,
+	mem,
+	ops::{Deref, DerefMut},
+	slice,
+	sync::Arc,
+};
+use tokio::{
+	runtime::Handle,
+	sync::{Mutex, RwLock},
 };
-use anyhow::Result;
-use futures::FutureExt;
-use sqlx::{postgres::PgPoolOptions, query, PgPool, Postgres, Row, Transaction};
-use std::{any::TypeId, collections::HashMap, fmt::Write, marker::PhantomData, mem, sync::Arc};
-use tokio::sync::RwLock;
 
 pub struct DbContextPool {
 	pool: Arc<PgPool>,
@@ -16,7 +43,7 @@ pub struct DbContextPool {
 }
 impl DbContextPool {
 	pub async fn new(uri: &str) -> Result<Self> {
-		let pool = Arc::new(PgPoolOptions::new().connect(&uri).await?);
+		let pool = Arc::new(PgPoolOptions::new().connect(uri).await?);
 		Ok(Self { pool, middlewares: Arc::default() })
 	}
 
@@ -29,157 +56,1762 @@ impl DbContextPool {
 	}
 }
 
+// Postgres's limit on the parameters of a single statement
+const MAX_PARAMETERS: usize = u16::MAX as usize;
+// appended to the `RETURNING` list of upserts; `xmax` is only 0 for a row version that was inserted rather than updated
+const INSERTED_COLUMN: &str = ", (xmax = 0)";
+
+// an entity's type and primary key, which is where the identity map keeps it
+type EntityId = (TypeId, Box<dyn Key + Send + Sync>);
+
+// a has_many relation of one parent: its type, the relation's name and its primary key
+type RelationKey = (TypeId, &'static str, Box<dyn Key + Send + Sync>);
+
+// builds a join entity of a many-to-many relation, once both sides have been saved
+type PendingJoin = Box<dyn Fn() -> BoxFuture<'static, Box<dyn Entity>> + Send + Sync>;
+
 /// Intended to be short-lived, such as for a single request.
 pub struct DbContext {
 	pool: Arc<PgPool>,
-	entities: HashMap<TypeId, HashMap<Box<dyn Key + Send + Sync>, Arc<RwLock<dyn Entity>>>>,
-	pending_entities: Vec<Arc<RwLock<dyn Entity>>>,
+	entities: HashMap<TypeId, HashMap<Box<dyn Key + Send + Sync>, TrackedEntity>>,
+	pending_entities: Vec<(TrackedEntity, Option<OnConflict>)>,
+	removed_entities: Vec<Arc<RwLock<dyn Entity>>>,
+	links: Vec<PendingJoin>,
+	unlinks: Vec<PendingJoin>,
+	// children of has_many relations that were already loaded, by parent type, relation name and parent key
+	relations: HashMap<RelationKey, Vec<TrackedEntity>>,
 	middlewares: Arc<RwLock<Vec<Arc<dyn EventListener + Send + Sync>>>>,
+	// opened by `begin`, and shared with the middleware chains that run on it
+	transaction: Option<Arc<Mutex<Transaction<'static, Postgres>>>>,
+	// tables written in that transaction, which the middleware is told about once it ends
+	written_tables: HashSet<&'static str>,
+	// entities inserted or updated in that transaction, which are forgotten if it's rolled back
+	written_entities: Vec<EntityId>,
 }
 impl DbContext {
 	pub fn new(pool: Arc<PgPool>, middlewares: Arc<RwLock<Vec<Arc<dyn EventListener + Send + Sync>>>>) -> Self {
-		Self { pool, entities: HashMap::new(), pending_entities: Vec::new(), middlewares }
+		Self {
+			pool,
+			entities: HashMap::new(),
+			pending_entities: Vec::new(),
+			removed_entities: Vec::new(),
+			links: Vec::new(),
+			unlinks: Vec::new(),
+			relations: HashMap::new(),
+			middlewares,
+			transaction: None,
+			written_tables: HashSet::new(),
+			written_entities: Vec::new(),
+		}
 	}
 
 	pub fn add<T: Entity>(&mut self, entity: T) -> Arc<RwLock<T>> {
 		let entity = Arc::new(RwLock::new(entity));
-		self.pending_entities.push(entity.clone());
+		self.pending_entities.push((TrackedEntity::new(entity.clone()), None));
 		entity
 	}
 
-	pub fn select<T: EntityExt>(&self) -> SelectBuilder<T> {
+	/// Like `add`, but if the entity conflicts with an existing row, that row is updated instead, as `upsert`
+	/// describes. Either way, the entity holds the resulting row after the next `save_changes`. That fails if the row
+	/// turns out to be tracked by another instance already, such as one loaded earlier, since changes to either one
+	/// would be lost; change that instance instead.
+	pub fn upsert<T: EntityExt>(&mut self, entity: T, upsert: Upsert<T>) -> Arc<RwLock<T>> {
+		let entity = Arc::new(RwLock::new(entity));
+		self.pending_entities.push((TrackedEntity::new(entity.clone()), Some(upsert.on_conflict)));
+		entity
+	}
+
+	/// Upserts on the primary key, updating every field that was set.
+	pub fn add_or_update<T: EntityExt>(&mut self, entity: T) -> Arc<RwLock<T>> {
+		self.upsert(entity, Upsert::primary_key())
+	}
+
+	/// Marks an entity for deletion on the next `save_changes`. Entities that were added but never saved are simply
+	/// forgotten.
+	pub fn remove<T: Entity>(&mut self, entity: &Arc<RwLock<T>>) {
+		let ptr = Arc::as_ptr(entity) as *const ();
+		let pending_len = self.pending_entities.len();
+		self.pending_entities.retain(|(x, _)| Arc::as_ptr(&x.entity) as *const () != ptr);
+		if self.pending_entities.len() == pending_len {
+			self.removed_entities.push(entity.clone());
+		}
+	}
+
+	/// Links `entity` to `other` through a many-to-many relation, such as `account::roles`, inserting the join row on
+	/// the next `save_changes`. Either side may still be waiting to be inserted itself, and linking entities that are
+	/// already linked does nothing.
+	pub fn link<T: Entity, U: Entity>(
+		&mut self,
+		relation: ManyToMany<T, U>,
+		entity: &Arc<RwLock<T>>,
+		other: &Arc<RwLock<U>>,
+	) {
+		self.links.push(Self::pending_join(relation, entity.clone(), other.clone()));
+	}
+
+	/// Removes a link made by `link`, deleting the join row on the next `save_changes`.
+	pub fn unlink<T: Entity, U: Entity>(
+		&mut self,
+		relation: ManyToMany<T, U>,
+		entity: &Arc<RwLock<T>>,
+		other: &Arc<RwLock<U>>,
+	) {
+		self.unlinks.push(Self::pending_join(relation, entity.clone(), other.clone()));
+	}
+
+	fn pending_join<T: Entity, U: Entity>(
+		relation: ManyToMany<T, U>,
+		entity: Arc<RwLock<T>>,
+		other: Arc<RwLock<U>>,
+	) -> PendingJoin {
+		Box::new(move || {
+			let (entity, other) = (entity.clone(), other.clone());
+			async move { (relation.join)(&*entity.read().await, &*other.read().await) }.boxed()
+		})
+	}
+
+	/// Looks up an entity by its primary key, only querying the database if this context isn't already tracking it.
+	/// Composite keys are passed as tuples, in the order the key fields were declared. Fails if `T` has no primary key.
+	pub async fn find<T: EntityExt, K>(&mut self, key: K) -> Result<Option<Arc<RwLock<T>>>>
+	where
+		K: Expression + Clone + Eq + Hash + Debug + Send + Sync + 'static,
+	{
+		if T::META.primary_key.is_empty() {
+			bail!("{} has no primary key to find it by", T::META.table_name);
+		}
+		let id: Box<dyn Key + Send + Sync> = Box::new(key.clone());
+		if let Some(entity) = self.entities.get(&TypeId::of::<T>()).and_then(|x| x.get(&id)) {
+			return Ok(entity.downcast());
+		}
+
+		self.select::<T>().filter(PrimaryKey::<T>::new().eq(key)).fetch_optional().await
+	}
+
+	pub fn select<T: EntityExt>(&mut self) -> SelectBuilder<'_, T> {
 		SelectBuilder::new(self)
 	}
 
+	/// Returns the `U`s whose `foreign_key` holds `key`, the primary key of a `T`. Children that were already loaded,
+	/// through this or `SelectBuilder::include`, are returned without querying the database again. Used by the
+	/// accessors the entity macro generates for `has_many` relations.
+	pub async fn has_many<T: EntityExt, U: EntityExt, K>(
+		&mut self,
+		relation: &'static str,
+		key: K,
+		foreign_key: Field<U>,
+	) -> Result<Vec<Arc<RwLock<U>>>>
+	where
+		K: Expression + Clone + Eq + Hash + Debug + Send + Sync + 'static,
+	{
+		let relation_key = (TypeId::of::<T>(), relation, Box::new(key.clone()) as Box<dyn Key + Send + Sync>);
+		if let Some(children) = self.relations.get(&relation_key) {
+			return Ok(children.iter().map(|x| x.downcast().unwrap()).collect());
+		}
+
+		let children = self.select::<U>().filter(foreign_key.eq(key)).fetch_all().await?;
+		self.relations.insert(relation_key, children.iter().cloned().map(TrackedEntity::new).collect());
+		Ok(children)
+	}
+
+	/// Returns the `U`s linked to the entity whose primary key is `key` through the join entity `J`, where `owner` is
+	/// the column of `J` holding `key` and `other` is the one referencing `U`. Used by the accessors the entity macro
+	/// generates for `many_to_many` relations.
+	pub async fn many_to_many<U: EntityExt, J: EntityExt, K>(
+		&mut self,
+		key: K,
+		owner: Field<J>,
+		other: Field<J>,
+	) -> Result<Vec<Arc<RwLock<U>>>>
+	where
+		K: Expression + Send + Sync + 'static,
+	{
+		let select = self.select::<U>().join::<J>(other.eq(PrimaryKey::<U>::new())).filter(owner.eq(key));
+		Ok(select.fetch_all().await?.into_iter().map(|(entity, _)| entity).collect())
+	}
+
+	/// Loads the children of a `has_many` relation for all of `parents` with one query, so later calls to
+	/// `has_many` for any of them don't need to query the database.
+	pub async fn load_has_many<T: EntityExt, U: EntityExt, K>(
+		&mut self,
+		relation: &'static str,
+		parents: &[Arc<RwLock<T>>],
+		foreign_key: Field<U>,
+		parent_key: impl Fn(&T) -> K + Send,
+		child_key: impl Fn(&U) -> Option<K> + Send,
+	) -> Result<()>
+	where
+		K: Clone + Eq + Hash + Debug + Send + Sync + 'static,
+		Vec<K>: Expression,
+	{
+		let mut keys = Vec::with_capacity(parents.len());
+		for parent in parents {
+			keys.push(parent_key(&*parent.read().await));
+		}
+
+		let children = self.select::<U>().filter(foreign_key.in_list(keys.clone())).fetch_all().await?;
+
+		let mut groups = keys.into_iter().map(|key| (key, Vec::new())).collect::<HashMap<_, _>>();
+		for child in children {
+			let key = child_key(&*child.read().await);
+			if let Some(group) = key.and_then(|key| groups.get_mut(&key)) {
+				group.push(TrackedEntity::new(child));
+			}
+		}
+		for (key, children) in groups {
+			self.relations.insert((TypeId::of::<T>(), relation, Box::new(key)), children);
+		}
+		Ok(())
+	}
+
+	/// Loads the `U`s referenced by a `belongs_to` relation of all of `parents` with one query, skipping any that are
+	/// already tracked, so later calls to `find` for them don't need to query the database.
+	pub async fn load_belongs_to<T: EntityExt, U: EntityExt, K>(
+		&mut self,
+		parents: &[Arc<RwLock<T>>],
+		foreign_key: impl Fn(&T) -> Option<K> + Send,
+	) -> Result<()>
+	where
+		K: Clone + Eq + Hash + Debug + Send + Sync + 'static,
+		Vec<K>: Expression,
+	{
+		let tracked = self.entities.get(&TypeId::of::<U>());
+		let mut keys = Vec::with_capacity(parents.len());
+		for parent in parents {
+			if let Some(key) = foreign_key(&*parent.read().await) {
+				let id: Box<dyn Key + Send + Sync> = Box::new(key.clone());
+				if !keys.iter().any(|x| x == &key) && !tracked.is_some_and(|x| x.contains_key(&id)) {
+					keys.push(key);
+				}
+			}
+		}
+		if keys.is_empty() {
+			return Ok(());
+		}
+
+		self.select::<U>().filter(PrimaryKey::<U>::new().in_list(keys)).fetch_all().await?;
+		Ok(())
+	}
+
+	/// Opens a transaction that every query and `save_changes` of this context runs on, until the returned guard is
+	/// committed or rolled back. Dropping the guard rolls the transaction back.
+	pub async fn begin(&mut self) -> Result<TransactionGuard<'_>> {
+		if self.transaction.is_some() {
+			bail!("a transaction is already open; use a savepoint to nest one");
+		}
+		self.transaction = Some(Arc::new(Mutex::new(self.pool.begin().await?)));
+		Ok(TransactionGuard { db_context: self })
+	}
+
+	/// Writes every pending change in a single transaction. If that fails, nothing in the context changes: the changes
+	/// stay pending and entities keep their unsaved values, so saving again retries all of it.
 	pub async fn save_changes(&mut self) -> Result<()> {
-		let mut pending_entities = mem::take(&mut self.pending_entities);
-		let mut transaction = self.pool.begin().await?;
-		for entity in pending_entities.drain(..) {
-			let (id, type_id) = {
-				let mut entity = entity.write().await;
-				let entity = &mut *entity;
+		// run on the transaction from `begin` if there is one, and otherwise on a new one that's committed here
+		let mut saved = SavedChanges::default();
+		let result = match self.transaction.clone() {
+			Some(shared) => {
+				let result = self.write_changes(&mut *shared.lock().await, &mut saved).await;
+				self.written_tables.extend(saved.tables.iter().copied());
+				result
+			},
+			None => {
+				let mut transaction = self.pool.begin().await?;
+				let mut result = self.write_changes(&mut transaction, &mut saved).await;
+				if result.is_ok() {
+					result = transaction.commit().await.map_err(Into::into);
+				}
+				result
+			},
+		};
+		if let Err(error) = result {
+			for (entity, snapshot) in saved.snapshots {
+				entity.write().await.apply(&snapshot);
+			}
+			if self.transaction.is_none() {
+				// the middleware has already seen the writes that were rolled back
+				Self::notify_transaction_end(self.middlewares.clone(), saved.tables).await?;
+			}
+			return Err(error);
+		}
+
+		self.pending_entities.clear();
+		self.removed_entities.clear();
+		self.links.clear();
+		self.unlinks.clear();
+		for (type_id, id) in saved.removed {
+			if let Some(entities) = self.entities.get_mut(&type_id) {
+				entities.remove(&id);
+			}
+		}
+		for ((type_id, id), tracked) in saved.inserted {
+			self.entities.entry(type_id).or_default().insert(id, tracked);
+		}
+		if self.transaction.is_some() {
+			self.written_entities.extend(saved.written);
+		}
+
+		// inserts, deletes and changed foreign keys can all move children between parents
+		self.relations.clear();
+		Ok(())
+	}
+
+	/// Writes the pending changes on `transaction`, recording in `saved` what `save_changes` has to apply to the
+	/// context afterwards, or undo.
+	async fn write_changes(&self, transaction: &mut Transaction<'_, Postgres>, saved: &mut SavedChanges) -> Result<()> {
+		for unlink in &self.unlinks {
+			let mut unlink = unlink().await;
+			saved.tables.insert(unlink.meta().table_name);
+			self.remove_entity(transaction, &mut *unlink).await?;
+		}
+
+		for entity in &self.removed_entities {
+			let mut entity = entity.write().await;
+			let entity = &mut *entity;
+
+			saved.tables.insert(entity.meta().table_name);
+			self.remove_entity(transaction, entity).await?;
+			saved.removed.push(((*entity).type_id(), entity.id()));
+		}
+
+		// update entities that were tracked before this call, and aren't being removed
+		let tracked_entities = {
+			// raw pointers aren't `Send`, so this can't be held across an await
+			let removed = self.removed_entities.iter().map(|x| Arc::as_ptr(x) as *const ()).collect::<HashSet<_>>();
+			let tracked_entities = self.entities.values().flat_map(|x| x.values().map(|x| x.entity.clone()));
+			tracked_entities.filter(|x| !removed.contains(&(Arc::as_ptr(x) as *const ()))).collect::<Vec<_>>()
+		};
+		for tracked in tracked_entities {
+			let mut entity = tracked.write().await;
+			let entity = &mut *entity;
+			if !entity.meta().fields.values().any(|field| Self::is_field_modified(entity, field)) {
+				continue;
+			}
+			saved.tables.insert(entity.meta().table_name);
+			saved.snapshots.push((tracked.clone(), entity.clone_dynamic()));
+			saved.written.push(((*entity).type_id(), entity.id()));
+
+			// build middleware chain
+			let middlewares = self.middlewares.read().await;
+			let mut next: UpdateNext = Box::new(move |transaction, entity| {
+				async move { Self::update_entity(transaction, entity).await }.boxed()
+			});
+			for middleware in middlewares.iter().cloned() {
+				next = Box::new(move |transaction, entity| middleware.update(transaction, entity, next));
+			}
+
+			next(transaction, entity).await?;
+		}
+
+		// consecutive entities of the same type with the same modified fields are inserted together, which keeps the
+		// order of inserts that depend on each other
+		let mut batch = Vec::new();
+		let mut batch_key = None;
+		for (tracked, on_conflict) in &self.pending_entities {
+			let key = {
+				let entity = tracked.entity.read().await;
+				let entity = &*entity;
+				saved.tables.insert(entity.meta().table_name);
+				let fields = entity.meta().fields.values();
+				let modified = fields.filter(|field| Self::is_field_modified(entity, field)).map(|field| field.name);
+				Some(((*entity).type_id(), modified.collect::<Vec<_>>()))
+			};
+
+			// upserts go one at a time, since a statement can't update a row twice, which two upserts of the same row
+			// in one batch would do
+			if on_conflict.is_some() {
+				self.flush_batch(transaction, mem::take(&mut batch), None, saved).await?;
+				batch_key = None;
+				self.flush_batch(transaction, vec![tracked.clone()], on_conflict.clone(), saved).await?;
+				continue;
+			}
+
+			if key != batch_key {
+				self.flush_batch(transaction, mem::take(&mut batch), None, saved).await?;
+				batch_key = key;
+			}
+			batch.push(tracked.clone());
+		}
+		self.flush_batch(transaction, batch, None, saved).await?;
+
+		// links go last, so the entities on both sides have been inserted, and linking twice is harmless
+		for link in &self.links {
+			let mut link = link().await;
+			saved.tables.insert(link.meta().table_name);
+			let on_conflict = OnConflict { target: vec![], action: ConflictAction::Nothing };
+			self.flush_entity(transaction, &mut *link, Some(on_conflict)).await?;
+		}
+		Ok(())
+	}
 
+	/// Tells the middleware which tables were written in a transaction that ended, whether it was committed or not.
+	async fn notify_transaction_end(
+		middlewares: Arc<RwLock<Vec<Arc<dyn EventListener + Send + Sync>>>>,
+		tables: HashSet<&'static str>,
+	) -> Result<()> {
+		if tables.is_empty() {
+			return Ok(());
+		}
+		let tables = tables.into_iter().collect::<Vec<_>>();
+		for middleware in middlewares.read().await.iter().cloned() {
+			middleware.transaction_end(&tables).await?;
+		}
+		Ok(())
+	}
+
+	// bulk writes in the transaction from `begin` have to be reported once it ends, like those of `save_changes`
+	fn record_write(&mut self, table_name: &'static str) {
+		if self.transaction.is_some() {
+			self.written_tables.insert(table_name);
+		}
+	}
+
+	// stops tracking the entities written in the transaction from `begin` since the `from`th write, after those
+	// writes were rolled back
+	fn forget_written_entities(&mut self, from: usize) {
+		for (type_id, key) in self.written_entities.drain(from..) {
+			if let Some(entities) = self.entities.get_mut(&type_id) {
+				entities.remove(&key);
+			}
+		}
+	}
+
+	// called once the transaction from `begin` is gone
+	async fn end_transaction(&mut self) -> Result<()> {
+		let tables = mem::take(&mut self.written_tables);
+		Self::notify_transaction_end(self.middlewares.clone(), tables).await
+	}
+
+	// where queries run while no middleware chain owns the transaction
+	fn connection(&self) -> DbConnection {
+		match &self.transaction {
+			Some(transaction) => DbConnection::Transaction(transaction.clone()),
+			None => DbConnection::Pool(self.pool.clone()),
+		}
+	}
+
+	/// Inserts entities of the same type with the same modified fields. Only a single entity can be upserted at a
+	/// time.
+	async fn flush_batch(
+		&self,
+		transaction: &mut Transaction<'_, Postgres>,
+		batch: Vec<TrackedEntity>,
+		on_conflict: Option<OnConflict>,
+		saved: &mut SavedChanges,
+	) -> Result<()> {
+		let upsert = on_conflict.is_some();
+		let mut guards = Vec::with_capacity(batch.len());
+		for tracked in &batch {
+			guards.push(tracked.entity.write().await);
+		}
+		saved.snapshots.extend(
+			batch.iter().zip(&guards).map(|(tracked, entity)| (tracked.entity.clone(), entity.clone_dynamic())),
+		);
+
+		let mut entities = guards.iter_mut().map(|x| &mut **x).collect::<Vec<_>>();
+		match &mut *entities {
+			[] => return Ok(()),
+			[entity] => {
+				if let Some(on_conflict) = &on_conflict {
+					on_conflict.check(entity.meta())?;
+				}
+				self.flush_entity(transaction, *entity, on_conflict).await?;
+			},
+			entities => {
+				assert!(on_conflict.is_none(), "upserts can't be batched");
 				// build middleware chain
 				let middlewares = self.middlewares.read().await;
-				let mut next: FlushNext = Box::new(move |transaction, entity| {
-					async move { Self::insert_entity(transaction, entity).await }.boxed()
+				let mut next: FlushManyNext = Box::new(move |transaction, entities| {
+					async move { Self::insert_entities(transaction, entities).await }.boxed()
 				});
 				for middleware in middlewares.iter().cloned() {
-					next = Box::new(move |transaction, entity| middleware.flush(transaction, entity, next));
+					next = Box::new(move |transaction, entities| middleware.flush_many(transaction, entities, next));
 				}
 
-				next(&mut transaction, entity).await?;
+				next(transaction, entities).await?;
+			},
+		}
 
-				(entity.id(), (*entity).type_id())
-			};
-			self.entities.entry(type_id).or_insert_with(HashMap::new).insert(id, entity);
+		for (tracked, entity) in batch.iter().zip(&guards) {
+			// like loaded rows, inserted ones without a primary key aren't tracked
+			if entity.meta().primary_key.is_empty() {
+				continue;
+			}
+			let id = ((**entity).type_id(), entity.id());
+			// an upsert can land on a row that an instance loaded earlier, or another upsert, already holds, and a row
+			// can only be tracked by one of them
+			if upsert {
+				let ptr = Arc::as_ptr(&tracked.entity) as *const ();
+				let tracked_row = self.entities.get(&id.0).and_then(|entities| entities.get(&id.1));
+				let inserted_row = saved.inserted.iter().filter(|(x, _)| *x == id).map(|(_, x)| x);
+				if tracked_row.into_iter().chain(inserted_row).any(|x| Arc::as_ptr(&x.entity) as *const () != ptr) {
+					bail!(
+						"{} {:?} is already tracked by another instance, so it can't be upserted; change that \
+						 instance instead",
+						entity.meta().table_name,
+						id.1
+					);
+				}
+			}
+			saved.written.push((id.0, entity.id()));
+			saved.inserted.push((id, tracked.clone()));
 		}
-		transaction.commit().await?;
 		Ok(())
 	}
 
-	async fn insert_entity(connection: &mut Transaction<'_, Postgres>, entity: &mut dyn Entity) -> Result<()> {
-		let fields = &entity.meta().fields;
+	/// Inserts an entity through the flush middleware chain.
+	async fn flush_entity(
+		&self,
+		transaction: &mut Transaction<'_, Postgres>,
+		entity: &mut dyn Entity,
+		on_conflict: Option<OnConflict>,
+	) -> Result<u64> {
+		// build middleware chain
+		let middlewares = self.middlewares.read().await;
+		let mut next: FlushNext = Box::new(move |transaction, entity| {
+			async move { Self::insert_entity(transaction, entity, on_conflict.as_ref()).await }.boxed()
+		});
+		for middleware in middlewares.iter().cloned() {
+			next = Box::new(move |transaction, entity| middleware.flush(transaction, entity, next));
+		}
 
-		let mut field_names = Vec::with_capacity(fields.len());
-		let mut modified_fields = Vec::with_capacity(fields.len());
-		let mut modified_field_names = Vec::with_capacity(fields.len());
-		let mut modified_field_params = Vec::with_capacity(fields.len());
-		for field in fields.values() {
-			let value = entity.field(field.name).unwrap();
-			let is_modified = match field.ty {
-				FieldType::I32 => value.downcast_ref::<EntityField<i32>>().unwrap().is_modified(),
-				FieldType::String => value.downcast_ref::<EntityField<String>>().unwrap().is_modified(),
-			};
+		next(transaction, entity).await
+	}
+
+	/// Deletes an entity through the remove middleware chain.
+	async fn remove_entity(&self, transaction: &mut Transaction<'_, Postgres>, entity: &mut dyn Entity) -> Result<u64> {
+		// build middleware chain
+		let middlewares = self.middlewares.read().await;
+		let mut next: RemoveNext =
+			Box::new(move |transaction, entity| async move { Self::delete_entity(transaction, entity).await }.boxed());
+		for middleware in middlewares.iter().cloned() {
+			next = Box::new(move |transaction, entity| middleware.remove(transaction, entity, next));
+		}
+
+		next(transaction, entity).await
+	}
+
+	/// Returns the number of rows inserted, which is 0 when an upsert updated an existing row instead.
+	async fn insert_entity(
+		connection: &mut Transaction<'_, Postgres>,
+		entity: &mut dyn Entity,
+		on_conflict: Option<&OnConflict>,
+	) -> Result<u64> {
+		let fields = entity.meta().fields.values();
+		let modified_fields = fields.filter(|field| Self::is_field_modified(entity, field)).collect::<Vec<_>>();
+		let sql = Self::insert_sql(entity.meta(), &modified_fields, on_conflict);
+
+		let mut query = query(&sql);
+		for field in modified_fields {
+			query = Self::bind_field(query, entity, field);
+		}
+		let result = match query.fetch_optional(connection).await? {
+			Some(result) => result,
+			None if on_conflict.is_some_and(|x| x.action == ConflictAction::Nothing) => return Ok(0),
+			None => return Err(sqlx::Error::RowNotFound.into()),
+		};
+		Self::load_entity(entity, &result)?;
+
+		// `INSERTED_COLUMN` comes after the entity's fields
+		let inserted = on_conflict.is_none() || result.try_get::<bool, _>(result.len() - 1)?;
+		Ok(inserted.into())
+	}
+
+	/// The statement `insert_entity` runs, which binds the values of `modified_fields` in order.
+	fn insert_sql(meta: &EntityMeta, modified_fields: &[&FieldMeta], on_conflict: Option<&OnConflict>) -> String {
+		let field_names = meta.fields.values().map(|field| format!("\"{}\"", field.name)).collect::<Vec<_>>();
+		let values = if modified_fields.is_empty() {
+			// an empty column list isn't valid
+			" DEFAULT VALUES".to_string()
+		} else {
+			let names = modified_fields.iter().map(|field| format!("\"{}\"", field.name)).collect::<Vec<_>>();
+			let params = (1..=modified_fields.len()).map(|i| format!("${}", i)).collect::<Vec<_>>();
+			format!(" ({}) VALUES ({})", names.join(", "), params.join(", "))
+		};
+
+		format!(
+			"INSERT INTO \"{}\"{}{} RETURNING {}{}",
+			meta.table_name,
+			values,
+			on_conflict.map(|x| x.to_sql(meta, modified_fields)).unwrap_or_default(),
+			field_names.join(", "),
+			if on_conflict.is_some() { INSERTED_COLUMN } else { "" },
+		)
+	}
+
+	/// Inserts entities of the same type with the same modified fields using multi-row `INSERT`s, as few as the
+	/// parameter limit allows.
+	async fn insert_entities(
+		connection: &mut Transaction<'_, Postgres>,
+		entities: &mut [&mut dyn Entity],
+	) -> Result<u64> {
+		let meta = entities[0].meta();
+		let field_names = meta.fields.values().map(|field| format!("\"{}\"", field.name)).collect::<Vec<_>>();
+		let modified_fields =
+			meta.fields.values().filter(|field| Self::is_field_modified(&*entities[0], field)).collect::<Vec<_>>();
+		if modified_fields.is_empty() {
+			// there's no multi-row form of `DEFAULT VALUES`
+			for entity in entities.iter_mut() {
+				Self::insert_entity(connection, *entity, None).await?;
+			}
+			return Ok(entities.len() as u64);
+		}
+		let modified_field_names =
+			modified_fields.iter().map(|field| format!("\"{}\"", field.name)).collect::<Vec<_>>();
+		// keys that are set before the insert show whether each entity got its own row back
+		let keys_known = !meta.primary_key.is_empty()
+			&& meta.primary_key.iter().all(|name| Self::is_field_modified(&*entities[0], &meta.fields[*name]));
+
+		for chunk in entities.chunks_mut(MAX_PARAMETERS / modified_fields.len()) {
+			let mut rows = Vec::with_capacity(chunk.len());
+			for i in 0..chunk.len() {
+				let params = (1..=modified_fields.len()).map(|j| format!("${}", i * modified_fields.len() + j));
+				rows.push(format!("({})", params.collect::<Vec<_>>().join(", ")));
+			}
+
+			let sql = format!(
+				"INSERT INTO \"{}\" ({}) VALUES {} RETURNING {}",
+				meta.table_name,
+				modified_field_names.join(", "),
+				rows.join(", "),
+				field_names.join(", "),
+			);
+
+			let mut query = query(&sql);
+			for entity in chunk.iter() {
+				for field in &modified_fields {
+					query = Self::bind_field(query, &**entity, field);
+				}
+			}
+			let results = query.fetch_all(&mut *connection).await?;
+			if results.len() != chunk.len() {
+				bail!("Inserted {} rows into {}, but got {} back", chunk.len(), meta.table_name, results.len());
+			}
+			// Postgres doesn't promise that `RETURNING` follows the order of the `VALUES` list, although it does in
+			// practice
+			for (entity, result) in chunk.iter_mut().zip(&results) {
+				let key = keys_known.then(|| entity.id());
+				Self::load_entity(*entity, result)?;
+				if let Some(key) = key {
+					if key != entity.id() {
+						bail!("Inserted rows of {} were returned out of order", meta.table_name);
+					}
+				}
+			}
+		}
+
+		Ok(entities.len() as u64)
+	}
+
+	async fn update_entity(connection: &mut Transaction<'_, Postgres>, entity: &mut dyn Entity) -> Result<()> {
+		let meta = entity.meta();
+		if meta.primary_key.is_empty() {
+			bail!("{} has no primary key, so its rows can't be updated one at a time", meta.table_name);
+		}
+		// the row is found by its key, and the entity only holds the new value of a changed one
+		if let Some(name) = meta.primary_key.iter().find(|name| Self::is_field_modified(entity, &meta.fields[**name])) {
+			bail!("the primary key field {} of {} can't be changed", name, meta.table_name);
+		}
+
+		let mut field_names = Vec::with_capacity(meta.fields.len());
+		let mut modified_fields = Vec::with_capacity(meta.fields.len());
+		let mut assignments = Vec::with_capacity(meta.fields.len());
+		for field in meta.fields.values() {
 			field_names.push(format!("\"{}\"", field.name));
-			if is_modified {
+			if Self::is_field_modified(entity, field) && meta.version.map(|x| x.column) != Some(field.name) {
 				modified_fields.push(field);
-				modified_field_names.push(format!("\"{}\"", field.name));
-				modified_field_params.push(format!("${}", modified_field_params.len() + 1));
+				assignments.push(format!("\"{}\" = ${}", field.name, assignments.len() + 1));
 			}
 		}
 
+		let mut key_fields = meta.primary_key.iter().map(|name| &meta.fields[*name]).collect::<Vec<_>>();
+		// the version is checked against the one the entity holds, like the key, and then incremented
+		if let Some(version) = &meta.version {
+			let column = format!("\"{}\"", version.column);
+			assignments.push(format!("{} = {}", column, (version.next)(&column)));
+			key_fields.push(&meta.fields[version.column]);
+		}
+		let key_conditions = key_fields
+			.iter()
+			.enumerate()
+			.map(|(i, field)| format!("\"{}\" = ${}", field.name, modified_fields.len() + i + 1))
+			.collect::<Vec<_>>();
+
 		let sql = format!(
-			"INSERT INTO \"{}\" ({}) VALUES ({}) RETURNING {}",
-			entity.meta().table_name,
-			modified_field_names.join(", "),
-			modified_field_params.join(", "),
+			"UPDATE \"{}\" SET {} WHERE {} RETURNING {}",
+			meta.table_name,
+			assignments.join(", "),
+			key_conditions.join(" AND "),
 			field_names.join(", "),
 		);
 
 		let mut query = query(&sql);
-		for field in modified_fields {
-			let value = entity.field(field.name).unwrap();
-			query = match field.ty {
-				FieldType::I32 => query.bind(value.downcast_ref::<EntityField<i32>>().unwrap().get()),
-				FieldType::String => query.bind(value.downcast_ref::<EntityField<String>>().unwrap().get()),
-			};
+		for field in modified_fields.into_iter().chain(key_fields) {
+			query = Self::bind_field(query, entity, field);
 		}
-		let result = query.fetch_one(connection).await?;
+		let result = match query.fetch_optional(connection).await? {
+			Some(result) => result,
+			None if meta.version.is_some() => {
+				return Err(ConcurrencyConflict { entity: meta.table_name, key: entity.id() }.into());
+			},
+			None => return Err(sqlx::Error::RowNotFound.into()),
+		};
+		Self::load_entity(entity, &result)?;
 
-		for field in fields.values() {
-			let value = entity.field_mut(field.name).unwrap();
-			match field.ty {
-				FieldType::I32 => {
-					*value.downcast_mut::<EntityField<i32>>().unwrap() = EntityField::Set(result.get(&field.name))
-				},
-				FieldType::String => {
-					*value.downcast_mut::<EntityField<String>>().unwrap() = EntityField::Set(result.get(&field.name))
-				},
+		Ok(())
+	}
+
+	/// Returns the number of rows deleted, which is 0 when the row was already gone.
+	async fn delete_entity(connection: &mut Transaction<'_, Postgres>, entity: &mut dyn Entity) -> Result<u64> {
+		let meta = entity.meta();
+		if meta.primary_key.is_empty() {
+			bail!("{} has no primary key, so its rows can't be deleted one at a time", meta.table_name);
+		}
+
+		let key_fields = meta.primary_key.iter().map(|name| &meta.fields[*name]).collect::<Vec<_>>();
+		let key_conditions = key_fields
+			.iter()
+			.enumerate()
+			.map(|(i, field)| format!("\"{}\" = ${}", field.name, i + 1))
+			.collect::<Vec<_>>();
+
+		let sql = format!("DELETE FROM \"{}\" WHERE {}", meta.table_name, key_conditions.join(" AND "));
+
+		let mut query = query(&sql);
+		for field in key_fields {
+			query = Self::bind_field(query, entity, field);
+		}
+		Ok(query.execute(connection).await?.rows_affected())
+	}
+
+	fn is_field_modified(entity: &dyn Entity, field: &FieldMeta) -> bool {
+		field.ty.is_modified(entity.field(field.name).unwrap())
+	}
+
+	fn bind_field<'q>(
+		query: Query<'q, Postgres, PgArguments>,
+		entity: &'q dyn Entity,
+		field: &FieldMeta,
+	) -> Query<'q, Postgres, PgArguments> {
+		field.ty.bind(query, entity.field(field.name).unwrap())
+	}
+
+	/// Overwrites every field of `entity` with the matching column of `row`, leaving them all `Set`.
+	fn load_entity(entity: &mut dyn Entity, row: &PgRow) -> Result<()> {
+		for field in entity.meta().fields.values() {
+			field.ty.load(entity.field_mut(field.name).unwrap(), row, field.name)?;
+		}
+		Ok(())
+	}
+
+	/// Like `load_entity`, but reads each field from a column named `"table.column"`, as joined queries select them.
+	fn load_qualified_entity(entity: &mut dyn Entity, row: &PgRow) -> Result<()> {
+		let table_name = entity.meta().table_name;
+		for field in entity.meta().fields.values() {
+			let column = format!("{}.{}", table_name, field.name);
+			field.ty.load(entity.field_mut(field.name).unwrap(), row, &column)?;
+		}
+		Ok(())
+	}
+
+	/// Registers a freshly loaded entity in the identity map. If the same row is already tracked, the existing
+	/// instance is returned instead, so any unsaved changes to it are kept. Rows without a primary key can't be told
+	/// apart, so they're never tracked, and each gets an instance of its own.
+	fn track<T: Entity>(&mut self, entity: T) -> Arc<RwLock<T>> {
+		if entity.meta().primary_key.is_empty() {
+			return Arc::new(RwLock::new(entity));
+		}
+		let entities = self.entities.entry(TypeId::of::<T>()).or_default();
+		let tracked = entities.entry(entity.id()).or_insert_with(|| TrackedEntity::new(Arc::new(RwLock::new(entity))));
+		tracked.downcast().unwrap()
+	}
+
+	/// Like `track`, but an instance that's already tracked is refreshed with the values of `entity`, which were read
+	/// from a locked row and so are the ones any update has to build on. Fails if that instance has unsaved changes.
+	async fn track_locked<T: Entity>(&mut self, entity: T) -> Result<Arc<RwLock<T>>> {
+		let tracked = self.entities.get(&TypeId::of::<T>()).and_then(|entities| entities.get(&entity.id()));
+		let tracked = match tracked {
+			Some(tracked) => tracked.downcast::<T>().unwrap(),
+			None => return Ok(self.track(entity)),
+		};
+
+		let mut existing = tracked.write().await;
+		if existing.meta().fields.values().any(|field| Self::is_field_modified(&*existing, field)) {
+			bail!(
+				"{} {:?} has unsaved changes, so it can't be refreshed from its locked row",
+				existing.meta().table_name,
+				existing.id()
+			);
+		}
+		*existing = entity;
+		drop(existing);
+		Ok(tracked)
+	}
+}
+
+/// A transaction opened by `DbContext::begin`, which derefs to the context. Dropping it without committing rolls it
+/// back.
+///
+/// Aggregates inside the transaction skip the middleware, and once it ends, the middleware is told which tables were
+/// written in it, so caches can drop what they hold for them.
+///
+/// Rolling back also stops tracking the entities that were inserted or updated during the transaction, since their
+/// values no longer match the database. Instances the caller still holds keep those values, and the next query loads
+/// fresh ones.
+pub struct TransactionGuard<'a> {
+	db_context: &'a mut DbContext,
+}
+impl<'a> TransactionGuard<'a> {
+	pub async fn commit(mut self) -> Result<()> {
+		let result = self.take().commit().await;
+		match result {
+			Ok(()) => self.db_context.written_entities.clear(),
+			// a failed commit rolls back
+			Err(_) => self.db_context.forget_written_entities(0),
+		}
+		self.db_context.end_transaction().await?;
+		Ok(result?)
+	}
+
+	pub async fn rollback(mut self) -> Result<()> {
+		self.db_context.relations.clear();
+		self.db_context.forget_written_entities(0);
+		let result = self.take().rollback().await;
+		self.db_context.end_transaction().await?;
+		Ok(result?)
+	}
+
+	/// Opens a savepoint inside this transaction.
+	pub async fn savepoint(&mut self) -> Result<Savepoint<'_>> {
+		Savepoint::begin(self.db_context).await
+	}
+
+	fn take(&mut self) -> Transaction<'static, Postgres> {
+		let transaction = self.db_context.transaction.take().unwrap();
+		match Arc::try_unwrap(transaction) {
+			Ok(transaction) => transaction.into_inner(),
+			Err(_) => panic!("the transaction is still in use"),
+		}
+	}
+}
+impl Deref for TransactionGuard<'_> {
+	type Target = DbContext;
+
+	fn deref(&self) -> &DbContext {
+		self.db_context
+	}
+}
+impl DerefMut for TransactionGuard<'_> {
+	fn deref_mut(&mut self) -> &mut DbContext {
+		self.db_context
+	}
+}
+impl Drop for TransactionGuard<'_> {
+	fn drop(&mut self) {
+		// sqlx rolls back a transaction that's dropped while still open
+		if self.db_context.transaction.take().is_some() {
+			self.db_context.relations.clear();
+			self.db_context.forget_written_entities(0);
+			let tables = mem::take(&mut self.db_context.written_tables);
+			// there's nowhere to report a failure from here, and nothing to notify from once the runtime is shut down
+			if !tables.is_empty() {
+				if let Ok(runtime) = Handle::try_current() {
+					let middlewares = self.db_context.middlewares.clone();
+					runtime.spawn(DbContext::notify_transaction_end(middlewares, tables));
+				}
 			}
 		}
+	}
+}
+
+/// A savepoint opened by `TransactionGuard::savepoint`, which derefs to the context. Dropping it without releasing it
+/// rolls back to it. Like the transaction, rolling back stops tracking the entities that were inserted or updated
+/// since the savepoint.
+pub struct Savepoint<'a> {
+	db_context: &'a mut DbContext,
+	open: bool,
+	// how many entities the transaction had written when the savepoint was opened
+	written_entities: usize,
+}
+impl<'a> Savepoint<'a> {
+	async fn begin(db_context: &'a mut DbContext) -> Result<Savepoint<'a>> {
+		PgTransactionManager::begin(&mut **db_context.transaction.as_ref().unwrap().lock().await).await?;
+		let written_entities = db_context.written_entities.len();
+		Ok(Self { db_context, open: true, written_entities })
+	}
+
+	/// Keeps everything done since the savepoint as part of the enclosing transaction.
+	pub async fn release(mut self) -> Result<()> {
+		self.open = false;
+		PgTransactionManager::commit(&mut **self.db_context.transaction.as_ref().unwrap().lock().await).await?;
+		Ok(())
+	}
+
+	/// Undoes everything done since the savepoint, leaving the enclosing transaction open.
+	pub async fn rollback(mut self) -> Result<()> {
+		self.open = false;
+		self.db_context.relations.clear();
+		self.db_context.forget_written_entities(self.written_entities);
+		PgTransactionManager::rollback(&mut **self.db_context.transaction.as_ref().unwrap().lock().await).await?;
+		Ok(())
+	}
+
+	/// Opens a savepoint inside this one.
+	pub async fn savepoint(&mut self) -> Result<Savepoint<'_>> {
+		Savepoint::begin(self.db_context).await
+	}
+}
+impl Deref for Savepoint<'_> {
+	type Target = DbContext;
+
+	fn deref(&self) -> &DbContext {
+		self.db_context
+	}
+}
+impl DerefMut for Savepoint<'_> {
+	fn deref_mut(&mut self) -> &mut DbContext {
+		self.db_context
+	}
+}
+impl Drop for Savepoint<'_> {
+	fn drop(&mut self) {
+		if !self.open {
+			return;
+		}
+		self.db_context.relations.clear();
+		self.db_context.forget_written_entities(self.written_entities);
+		// nothing else can hold the lock while the savepoint is borrowing the context, and the rollback is sent along
+		// with the next query
+		if let Ok(mut transaction) = self.db_context.transaction.as_ref().unwrap().try_lock() {
+			PgTransactionManager::start_rollback(&mut **transaction);
+		}
+	}
+}
+
+/// The error `save_changes` fails with when an entity with a version field was changed or deleted since it was
+/// loaded, which can be told apart from other errors with `anyhow::Error::downcast_ref`.
+#[derive(Debug)]
+pub struct ConcurrencyConflict {
+	/// The entity's table name.
+	pub entity: &'static str,
+	pub key: Box<dyn Key + Send + Sync>,
+}
+impl fmt::Display for ConcurrencyConflict {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} {:?} was changed or deleted since it was loaded", self.entity, self.key)
+	}
+}
+impl Error for ConcurrencyConflict {}
+
+/// Where a context's queries run: straight on the pool, or on the transaction opened by `DbContext::begin`.
+#[derive(Clone)]
+enum DbConnection {
+	Pool(Arc<PgPool>),
+	Transaction(Arc<Mutex<Transaction<'static, Postgres>>>),
+}
+impl DbConnection {
+	async fn fetch_all(&self, query: Query<'_, Postgres, PgArguments>) -> Result<Vec<PgRow>> {
+		Ok(match self {
+			Self::Pool(pool) => query.fetch_all(&**pool).await?,
+			Self::Transaction(transaction) => query.fetch_all(&mut *transaction.lock().await).await?,
+		})
+	}
+
+	async fn fetch_one(&self, query: Query<'_, Postgres, PgArguments>) -> Result<PgRow> {
+		Ok(match self {
+			Self::Pool(pool) => query.fetch_one(&**pool).await?,
+			Self::Transaction(transaction) => query.fetch_one(&mut *transaction.lock().await).await?,
+		})
+	}
+
+	async fn fetch_optional(&self, query: Query<'_, Postgres, PgArguments>) -> Result<Option<PgRow>> {
+		Ok(match self {
+			Self::Pool(pool) => query.fetch_optional(&**pool).await?,
+			Self::Transaction(transaction) => query.fetch_optional(&mut *transaction.lock().await).await?,
+		})
+	}
+
+	/// Returns the number of affected rows.
+	async fn execute(&self, query: Query<'_, Postgres, PgArguments>) -> Result<u64> {
+		let result = match self {
+			Self::Pool(pool) => query.execute(&**pool).await?,
+			Self::Transaction(transaction) => query.execute(&mut *transaction.lock().await).await?,
+		};
+		Ok(result.rows_affected())
+	}
+}
+
+/// How `DbContext::upsert` resolves a conflict with an existing row.
+pub struct Upsert<T> {
+	on_conflict: OnConflict,
+	phantom: PhantomData<T>,
+}
+impl<T: EntityExt> Upsert<T> {
+	/// Conflicts on the primary key, updating every field that was set. Saving fails if `T` has no primary key.
+	pub fn primary_key() -> Self {
+		let on_conflict = OnConflict { target: T::META.primary_key.to_vec(), action: ConflictAction::Update(None) };
+		Self { on_conflict, phantom: PhantomData }
+	}
+
+	/// Conflicts on a unique constraint declared with `#[unique(...)]`, whose fields can be given in any order,
+	/// updating every field that was set. Saving fails if `T` has no such constraint.
+	pub fn unique(fields: &[Field<T>]) -> Self {
+		let target = fields.iter().map(|field| field.name).collect::<Vec<_>>();
+		Self { on_conflict: OnConflict { target, action: ConflictAction::Update(None) }, phantom: PhantomData }
+	}
 
+	/// Only updates these fields of the existing row, instead of every field that was set.
+	pub fn update(mut self, fields: &[Field<T>]) -> Self {
+		self.on_conflict.action = ConflictAction::Update(Some(fields.iter().map(|field| field.name).collect()));
+		self
+	}
+}
+
+#[derive(Clone, PartialEq, Eq)]
+struct OnConflict {
+	// empty for `DO NOTHING` on any unique constraint
+	target: Vec<&'static str>,
+	action: ConflictAction,
+}
+#[derive(Clone, PartialEq, Eq)]
+enum ConflictAction {
+	// `None` updates every inserted column outside the target
+	Update(Option<Vec<&'static str>>),
+	// leaves the existing row as it is, which isn't returned either
+	Nothing,
+}
+impl OnConflict {
+	/// Fails if the target isn't the primary key or a unique constraint of `meta`, which Postgres would reject.
+	fn check(&self, meta: &EntityMeta) -> Result<()> {
+		if self.target.is_empty() {
+			if self.action != ConflictAction::Nothing {
+				bail!("{} has no primary key to upsert on", meta.table_name);
+			}
+			return Ok(());
+		}
+		let matches = |columns: &[&str]| {
+			columns.len() == self.target.len() && columns.iter().all(|x| self.target.iter().any(|y| x == y))
+		};
+		if !matches(meta.primary_key) && !meta.unique.iter().any(|&columns| matches(columns)) {
+			bail!("{} has no unique constraint on {:?}", meta.table_name, self.target);
+		}
 		Ok(())
 	}
+
+	fn to_sql(&self, meta: &EntityMeta, modified_fields: &[&FieldMeta]) -> String {
+		let target = self.target.iter().map(|column| format!("\"{}\"", column)).collect::<Vec<_>>();
+		let target = if target.is_empty() { String::new() } else { format!(" ({})", target.join(", ")) };
+		let mut update = match &self.action {
+			ConflictAction::Update(Some(update)) => update.clone(),
+			ConflictAction::Update(None) => {
+				modified_fields.iter().map(|field| field.name).filter(|x| !self.target.iter().any(|y| x == y)).collect()
+			},
+			ConflictAction::Nothing => return format!(" ON CONFLICT{} DO NOTHING", target),
+		};
+		// the version of the existing row is incremented rather than overwritten
+		if let Some(version) = &meta.version {
+			update.retain(|column| *column != version.column);
+		}
+
+		let mut assignments =
+			update.iter().map(|column| format!("\"{0}\" = EXCLUDED.\"{0}\"", column)).collect::<Vec<_>>();
+		if let Some(version) = &meta.version {
+			let column = format!("\"{}\".\"{}\"", meta.table_name, version.column);
+			assignments.push(format!("\"{}\" = {}", version.column, (version.next)(&column)));
+		}
+		// unlike `DO NOTHING`, a no-op update still returns the existing row
+		if assignments.is_empty() {
+			assignments.push(format!("\"{0}\" = EXCLUDED.\"{0}\"", self.target[0]));
+		}
+
+		format!(" ON CONFLICT{} DO UPDATE SET {}", target, assignments.join(", "))
+	}
+}
+
+/// What a `save_changes` call has written, which it applies to the context once the writes are committed, or undoes
+/// if they fail.
+#[derive(Default)]
+struct SavedChanges {
+	tables: HashSet<&'static str>,
+	// the values written entities had before, to restore their unsaved state
+	snapshots: Vec<(Arc<RwLock<dyn Entity>>, DynamicStruct)>,
+	removed: Vec<EntityId>,
+	// inserted and updated entities
+	written: Vec<EntityId>,
+	inserted: Vec<(EntityId, TrackedEntity)>,
+}
+
+#[derive(Clone)]
+struct TrackedEntity {
+	entity: Arc<RwLock<dyn Entity>>,
+	// the same allocation as `entity`, kept around so it can be handed back out with its concrete type
+	typed: Arc<dyn Any + Send + Sync>,
+}
+impl TrackedEntity {
+	fn new<T: Entity>(entity: Arc<RwLock<T>>) -> Self {
+		Self { entity: entity.clone(), typed: entity }
+	}
+
+	fn downcast<T: Entity>(&self) -> Option<Arc<RwLock<T>>> {
+		self.typed.clone().downcast().ok()
+	}
 }
 
 pub struct SelectBuilder<'a, T> {
-	db_context: &'a DbContext,
-	filter: Option<Box<dyn Predicate + Send + Sync + 'a>>,
+	db_context: &'a mut DbContext,
+	clauses: SelectClauses<'a>,
+	includes: Vec<Relation<T>>,
 	phantom: PhantomData<T>,
 }
 impl<'a, T: EntityExt> SelectBuilder<'a, T> {
-	pub fn new(db_context: &'a DbContext) -> Self {
-		Self { db_context, filter: None, phantom: PhantomData }
+	pub fn new(db_context: &'a mut DbContext) -> Self {
+		Self { db_context, clauses: SelectClauses::default(), includes: Vec::new(), phantom: PhantomData }
 	}
 
+	/// Restricts the rows this query matches. Calling it again ANDs the new predicate onto the previous ones.
 	pub fn filter(mut self, predicate: impl Predicate + Send + Sync + 'a) -> Self {
-		self.filter = Some(Box::new(predicate));
+		self.clauses.filter = Some(match self.clauses.filter.take() {
+			Some(filter) => Box::new(filter.and(predicate)),
+			None => Box::new(predicate),
+		});
+		self
+	}
+
+	/// Adds a sort key, such as `account::id.desc()`. Earlier calls take precedence over later ones.
+	pub fn order_by(mut self, order: OrderBy<'a>) -> Self {
+		self.clauses.order.push(order);
+		self
+	}
+
+	pub fn limit(mut self, limit: i64) -> Self {
+		self.clauses.limit = Some(limit);
+		self
+	}
+
+	pub fn offset(mut self, offset: i64) -> Self {
+		self.clauses.offset = Some(offset);
+		self
+	}
+
+	/// Keyset pagination: only matches rows that sort after `cursor`, which holds the values of the `order_by`
+	/// expressions for the last row of the previous page, as a tuple if there are several. There must be at least one
+	/// ordering, and every ordering must use the same direction, or fetching fails.
+	pub fn after(mut self, cursor: impl Expression + Send + Sync + 'a) -> Self {
+		self.clauses.after = Some(Box::new(cursor));
+		self
+	}
+
+	/// Locks the matching rows with `FOR UPDATE` until the end of the transaction, which this query has to run in.
+	/// Entities this context already tracks are refreshed with the locked row's values, which fails if they have
+	/// unsaved changes.
+	pub fn for_update(self) -> Self {
+		self.lock(LockStrength::Update)
+	}
+
+	/// Like `for_update`, but with `FOR SHARE`, which other transactions can also take.
+	pub fn for_share(self) -> Self {
+		self.lock(LockStrength::Share)
+	}
+
+	/// Fails instead of waiting for rows that another transaction has locked. Needs `for_update` or `for_share`, before
+	/// or after it, or fetching fails.
+	pub fn nowait(self) -> Self {
+		self.lock_wait(LockWait::NoWait)
+	}
+
+	/// Leaves out rows that another transaction has locked instead of waiting for them, such as to take jobs off a
+	/// queue. Needs `for_update` or `for_share`, like `nowait`.
+	pub fn skip_locked(self) -> Self {
+		self.lock_wait(LockWait::SkipLocked)
+	}
+
+	/// Eager-loads a relation, such as `account::posts`, for every entity this query returns, with one extra query
+	/// instead of one per entity.
+	pub fn include(mut self, relation: Relation<T>) -> Self {
+		self.includes.push(relation);
+		self
+	}
+
+	pub async fn fetch_all(mut self) -> Result<Vec<Arc<RwLock<T>>>> {
+		let sql = self.build_select();
+		let rows = self.connection()?.fetch_all(self.clauses.bind_to(sql.to_query())).await?;
+		let mut entities = Vec::with_capacity(rows.len());
+		for row in &rows {
+			entities.push(self.track(Self::load(row)?).await?);
+		}
+		self.load_includes(&entities).await?;
+		Ok(entities)
+	}
+
+	pub async fn fetch_one(mut self) -> Result<Arc<RwLock<T>>> {
+		let sql = self.build_select();
+		let row = self.connection()?.fetch_one(self.clauses.bind_to(sql.to_query())).await?;
+		let entity = self.track(Self::load(&row)?).await?;
+		self.load_includes(slice::from_ref(&entity)).await?;
+		Ok(entity)
+	}
+
+	pub async fn fetch_optional(mut self) -> Result<Option<Arc<RwLock<T>>>> {
+		let sql = self.build_select();
+		let row = self.connection()?.fetch_optional(self.clauses.bind_to(sql.to_query())).await?;
+		let entity = match row {
+			Some(row) => Some(self.track(Self::load(&row)?).await?),
+			None => None,
+		};
+		if let Some(entity) = &entity {
+			self.load_includes(slice::from_ref(entity)).await?;
+		}
+		Ok(entity)
+	}
+
+	// locked rows refresh the entities that are already tracked
+	async fn track(&mut self, entity: T) -> Result<Arc<RwLock<T>>> {
+		match self.clauses.lock {
+			Some(_) => self.db_context.track_locked(entity).await,
+			None => Ok(self.db_context.track(entity)),
+		}
+	}
+
+	fn lock(mut self, strength: LockStrength) -> Self {
+		let wait = self.clauses.lock.map(|lock| lock.wait).or(self.clauses.lock_wait.take()).unwrap_or(LockWait::Wait);
+		self.clauses.lock = Some(Lock { strength, table_name: T::META.table_name, wait });
+		self
+	}
+
+	fn lock_wait(mut self, wait: LockWait) -> Self {
+		match &mut self.clauses.lock {
+			Some(lock) => lock.wait = wait,
+			None => self.clauses.lock_wait = Some(wait),
+		}
 		self
 	}
 
+	// where this query runs, once its clauses are known to be valid, which has to be an explicit transaction for locks
+	// to mean anything
+	fn connection(&self) -> Result<DbConnection> {
+		self.clauses.check()?;
+		if self.clauses.lock.is_some() && self.db_context.transaction.is_none() {
+			bail!("locking queries need a transaction from DbContext::begin");
+		}
+		Ok(self.db_context.connection())
+	}
+
+	async fn load_includes(&mut self, entities: &[Arc<RwLock<T>>]) -> Result<()> {
+		if entities.is_empty() {
+			return Ok(());
+		}
+		for relation in &self.includes {
+			(relation.load)(self.db_context, entities).await?;
+		}
+		Ok(())
+	}
+
+	/// Like `fetch_optional`, but limits the query to a single row instead of expecting the filter to match at most
+	/// one.
+	pub async fn first(self) -> Result<Option<Arc<RwLock<T>>>> {
+		self.limit(1).fetch_optional().await
+	}
+
+	/// Joins every matching `U`, such as `select::<Post>().join::<Account>(post::author_id.eq(account::id))`. Later
+	/// filters and orderings can reference the fields of both entities.
+	/// Fetching fails if `U` is `T`, since its columns couldn't be told apart.
+	pub fn join<U: EntityExt>(
+		self,
+		on: impl Predicate + Send + Sync + 'a,
+	) -> JoinedSelectBuilder<'a, T, Arc<RwLock<U>>> {
+		JoinedSelectBuilder::new(self, on)
+	}
+
+	/// Like `join`, but keeps the rows of `T` that no `U` matches, pairing them with `None`.
+	pub fn left_join<U: EntityExt>(
+		self,
+		on: impl Predicate + Send + Sync + 'a,
+	) -> JoinedSelectBuilder<'a, T, Option<Arc<RwLock<U>>>> {
+		JoinedSelectBuilder::new(self, on)
+	}
+
+	/// Switches to a grouped aggregate query, such as `select::<Order>().group_by(order::customer_id).count()`.
+	pub fn group_by(mut self, key: impl Expression + Send + Sync + 'a) -> GroupedSelectBuilder<'a, T> {
+		self.clauses.group_by = Some(Box::new(key));
+		GroupedSelectBuilder { select: self }
+	}
+
 	pub async fn count(self) -> Result<i64> {
+		self.aggregate("COUNT", None).await
+	}
+
+	pub async fn exists(self) -> Result<bool> {
+		self.aggregate("EXISTS", None).await
+	}
+
+	/// Updates every matching row with a single statement, such as
+	/// `.update(|s| s.set(session::expired, true))`, returning how many there were. Entities this context already
+	/// tracks keep their old values.
+	pub async fn update(self, set: impl FnOnce(UpdateSet<'a, T>) -> UpdateSet<'a, T>) -> Result<u64> {
+		let assignments = set(UpdateSet::new()).assignments;
+		if assignments.0.is_empty() {
+			bail!("update needs at least one assignment");
+		}
+		self.db_context.record_write(T::META.table_name);
+
+		// build middleware chain
 		let middlewares = self.db_context.middlewares.read().await;
-		let next = self.build_aggregate_middleware(middlewares.iter().cloned()).await;
-		next("COUNT", T::META, self.filter.as_ref()).await
+		let connection = self.connection()?;
+		let mut next: BulkUpdateNext = Box::new(move |entity_meta, assignments, clauses| {
+			async move {
+				let mut sql = SqlBuilder::new();
+				write!(sql, "UPDATE \"{}\" SET ", entity_meta.table_name).unwrap();
+				assignments.push_to(&mut sql);
+				// changed rows get a new version, so entities loaded before conflict when they're saved
+				if let Some(version) = &entity_meta.version {
+					if !assignments.0.iter().any(|(column, _)| *column == version.column) {
+						let column = format!("\"{}\"", version.column);
+						write!(sql, ", {} = {}", column, (version.next)(&column)).unwrap();
+					}
+				}
+				clauses.push_filter_to(&mut sql, entity_meta);
+				let query = clauses.bind_to(assignments.bind_to(sql.to_query()));
+				connection.execute(query).await
+			}
+			.boxed()
+		});
+		for middleware in middlewares.iter().cloned() {
+			next = Box::new(move |entity_meta, assignments, clauses| {
+				middleware.bulk_update(entity_meta, assignments, clauses, next)
+			});
+		}
+
+		let count = next(T::META, &assignments, &self.clauses).await?;
+		drop(middlewares);
+		self.db_context.relations.clear();
+		Ok(count)
+	}
+
+	/// Deletes every matching row with a single statement, returning how many there were. Entities this context
+	/// already tracks aren't forgotten.
+	pub async fn delete(self) -> Result<u64> {
+		self.db_context.record_write(T::META.table_name);
+		// build middleware chain
+		let middlewares = self.db_context.middlewares.read().await;
+		let connection = self.connection()?;
+		let mut next: BulkDeleteNext = Box::new(move |entity_meta, clauses| {
+			async move {
+				let mut sql = SqlBuilder::new();
+				write!(sql, "DELETE FROM \"{}\"", entity_meta.table_name).unwrap();
+				clauses.push_filter_to(&mut sql, entity_meta);
+				connection.execute(clauses.bind_to(sql.to_query())).await
+			}
+			.boxed()
+		});
+		for middleware in middlewares.iter().cloned() {
+			next = Box::new(move |entity_meta, clauses| middleware.bulk_delete(entity_meta, clauses, next));
+		}
+
+		let count = next(T::META, &self.clauses).await?;
+		drop(middlewares);
+		self.db_context.relations.clear();
+		Ok(count)
+	}
+
+	/// Returns `None` when no rows match.
+	pub async fn sum<R: FromAggregateValue>(self, field: Field<T>) -> Result<Option<R>> {
+		self.aggregate("SUM", Some(field.name)).await
+	}
+
+	/// Always computed as `double precision`. Returns `None` when no rows match.
+	pub async fn avg(self, field: Field<T>) -> Result<Option<f64>> {
+		self.aggregate("AVG", Some(field.name)).await
+	}
+
+	/// Returns `None` when no rows match.
+	pub async fn min<R: FromAggregateValue>(self, field: Field<T>) -> Result<Option<R>> {
+		self.aggregate("MIN", Some(field.name)).await
+	}
+
+	/// Returns `None` when no rows match.
+	pub async fn max<R: FromAggregateValue>(self, field: Field<T>) -> Result<Option<R>> {
+		self.aggregate("MAX", Some(field.name)).await
+	}
+
+	async fn aggregate<R: FromAggregateValue>(
+		self,
+		operation: &'static str,
+		target: Option<&'static str>,
+	) -> Result<R> {
+		let middlewares = self.db_context.middlewares.read().await;
+		// locked rows, and rows written by a transaction that hasn't ended, have to be read from the database, not from
+		// a cache
+		let uncached = self.clauses.lock.is_some() || self.db_context.transaction.is_some();
+		let middlewares = if uncached { &[][..] } else { &middlewares[..] };
+		let next = self.build_aggregate_middleware(middlewares.iter().cloned()).await?;
+		R::from_aggregate_value(next(operation, T::META, target, &self.clauses).await?)
+	}
+
+	fn build_select(&self) -> SqlBuilder {
+		let mut sql = SqlBuilder::new();
+		let columns = T::META.fields.values().map(|field| format!("\"{}\".\"{}\"", T::META.table_name, field.name));
+		write!(sql, "SELECT {} FROM \"{}\"", columns.collect::<Vec<_>>().join(", "), T::META.table_name).unwrap();
+		self.clauses.push_to(&mut sql);
+		sql
+	}
+
+	fn load(row: &PgRow) -> Result<T> {
+		let mut entity = T::new();
+		DbContext::load_entity(&mut entity, row)?;
+		Ok(entity)
+	}
+
+	/// The columns of `T`, each selected as `"table.column"` so they can't collide with those of a joined entity.
+	fn qualified_columns() -> impl Iterator<Item = String> {
+		let table_name = T::META.table_name;
+		T::META.fields.values().map(move |field| format!("\"{0}\".\"{1}\" AS \"{0}.{1}\"", table_name, field.name))
 	}
 
 	async fn build_aggregate_middleware(
 		&self,
 		middlewares: impl Iterator<Item = Arc<dyn EventListener + Send + Sync>>,
-	) -> AggregateNext {
-		let pool = self.db_context.pool.clone();
-		let mut next: AggregateNext = Box::new(move |operation, entity_meta, filter| {
+	) -> Result<AggregateNext> {
+		let connection = self.connection()?;
+		let mut next: AggregateNext = Box::new(move |operation, entity_meta, target, clauses| {
 			async move {
+				let table_name = entity_meta.table_name;
+				let column = target.map(|x| format!("\"{}\".\"{}\"", table_name, x));
+				let argument = column.as_deref().unwrap_or("*");
+				let column_type = target.map(|x| entity_meta.fields[x].ty.column_type());
+				let cast = match operation {
+					"AVG" => "::double precision",
+					// the sum of a bigint column is numeric, which only the decimal feature can decode
+					"SUM" if column_type == Some("bigint") => "::bigint",
+					_ => "",
+				};
 				let mut sql = SqlBuilder::new();
-				write!(sql, "SELECT {}(*) FROM \"{}\"", operation, entity_meta.table_name).unwrap();
-				if let Some(filter) = filter {
-					write!(sql, " WHERE ").unwrap();
-					filter.push_to(&mut sql);
+				if let Some(group_by) = &clauses.group_by {
+					sql.push("SELECT ");
+					group_by.push_to(&mut sql);
+					write!(sql, ", {}({}){} FROM \"{}\"", operation, argument, cast, table_name).unwrap();
+					clauses.push_to(&mut sql);
+					let query = clauses.bind_to(group_by.bind_to(sql.to_query()));
+					return AggregateValue::decode_groups(&connection.fetch_all(query).await?);
 				}
-				let mut query = sql.to_query();
-				if let Some(filter) = filter {
-					query = filter.bind_to(query);
+
+				if operation == "EXISTS" {
+					write!(sql, "SELECT EXISTS (SELECT 1 FROM \"{}\"", table_name).unwrap();
+					clauses.push_to(&mut sql);
+					sql.push(")");
+				} else if clauses.is_windowed() {
+					let inner_column = column.as_deref().unwrap_or("1");
+					write!(
+						sql,
+						"SELECT {}({}){} FROM (SELECT {} FROM \"{}\"",
+						operation, argument, cast, inner_column, table_name
+					)
+					.unwrap();
+					clauses.push_to(&mut sql);
+					write!(sql, ") AS \"{}\"", table_name).unwrap();
+				} else {
+					write!(sql, "SELECT {}({}){} FROM \"{}\"", operation, argument, cast, table_name).unwrap();
+					clauses.push_to(&mut sql);
 				}
-				Ok(query.fetch_one(&*pool).await?.get(0))
+				let query = clauses.bind_to(sql.to_query());
+				AggregateValue::decode(&connection.fetch_one(query).await?, 0)
 			}
 			.boxed()
 		});
 		for middleware in middlewares {
-			next = Box::new(move |operation, entity_meta, filter| {
-				middleware.aggregate(operation, entity_meta, filter, next)
+			next = Box::new(move |operation, entity_meta, target, clauses| {
+				middleware.aggregate(operation, entity_meta, target, clauses, next)
 			});
 		}
-		next
+		Ok(next)
+	}
+}
+
+/// A `SelectBuilder` with a `GROUP BY` clause. Its aggregates return one `(key, value)` pair per group, which can be
+/// collected into a map if the order doesn't matter.
+pub struct GroupedSelectBuilder<'a, T> {
+	select: SelectBuilder<'a, T>,
+}
+impl<'a, T: EntityExt> GroupedSelectBuilder<'a, T> {
+	/// Restricts which groups are returned, usually with a predicate over an aggregate such as
+	/// `query::count_all().gt(1)`. Calling it again ANDs the new predicate onto the previous ones.
+	pub fn having(mut self, predicate: impl Predicate + Send + Sync + 'a) -> Self {
+		let clauses = &mut self.select.clauses;
+		clauses.having = Some(match clauses.having.take() {
+			Some(having) => Box::new(having.and(predicate)),
+			None => Box::new(predicate),
+		});
+		self
+	}
+
+	pub fn order_by(mut self, order: OrderBy<'a>) -> Self {
+		self.select = self.select.order_by(order);
+		self
+	}
+
+	pub fn limit(mut self, limit: i64) -> Self {
+		self.select = self.select.limit(limit);
+		self
+	}
+
+	pub fn offset(mut self, offset: i64) -> Self {
+		self.select = self.select.offset(offset);
+		self
+	}
+
+	pub async fn count<K: FromAggregateValue>(self) -> Result<Vec<(K, i64)>> {
+		self.select.aggregate("COUNT", None).await
+	}
+
+	pub async fn sum<K: FromAggregateValue, R: FromAggregateValue>(
+		self,
+		field: Field<T>,
+	) -> Result<Vec<(K, Option<R>)>> {
+		self.select.aggregate("SUM", Some(field.name)).await
+	}
+
+	pub async fn avg<K: FromAggregateValue>(self, field: Field<T>) -> Result<Vec<(K, Option<f64>)>> {
+		self.select.aggregate("AVG", Some(field.name)).await
+	}
+
+	pub async fn min<K: FromAggregateValue, R: FromAggregateValue>(
+		self,
+		field: Field<T>,
+	) -> Result<Vec<(K, Option<R>)>> {
+		self.select.aggregate("MIN", Some(field.name)).await
+	}
+
+	pub async fn max<K: FromAggregateValue, R: FromAggregateValue>(
+		self,
+		field: Field<T>,
+	) -> Result<Vec<(K, Option<R>)>> {
+		self.select.aggregate("MAX", Some(field.name)).await
+	}
+}
+
+/// How the joined entity of a `JoinedSelectBuilder` is returned: `Arc<RwLock<U>>` for inner joins, or an `Option` of
+/// one for left joins, which is `None` when no row of `U` matched.
+pub trait JoinResult: Sized {
+	type Entity: EntityExt;
+	const JOIN: &'static str;
+
+	fn load(db_context: &mut DbContext, row: &PgRow) -> Result<Self>;
+}
+impl<U: EntityExt> JoinResult for Arc<RwLock<U>> {
+	type Entity = U;
+
+	const JOIN: &'static str = "INNER JOIN";
+
+	fn load(db_context: &mut DbContext, row: &PgRow) -> Result<Self> {
+		let mut entity = U::new();
+		DbContext::load_qualified_entity(&mut entity, row)?;
+		Ok(db_context.track(entity))
+	}
+}
+impl<U: EntityExt> JoinResult for Option<Arc<RwLock<U>>> {
+	type Entity = U;
+
+	const JOIN: &'static str = "LEFT JOIN";
+
+	fn load(db_context: &mut DbContext, row: &PgRow) -> Result<Self> {
+		// the key columns can only all be null if nothing matched
+		let key = if U::META.primary_key.is_empty() {
+			U::META.fields.keys().copied().collect::<Vec<_>>()
+		} else {
+			U::META.primary_key.to_vec()
+		};
+		for name in key {
+			if !row.try_get_raw(&*format!("{}.{}", U::META.table_name, name))?.is_null() {
+				return Ok(Some(Arc::<RwLock<U>>::load(db_context, row)?));
+			}
+		}
+		Ok(None)
+	}
+}
+
+/// A `SelectBuilder` joined to a second entity, returning `(T, U)` pairs for `join` or `(T, Option<U>)` pairs for
+/// `left_join`.
+pub struct JoinedSelectBuilder<'a, T, J> {
+	select: SelectBuilder<'a, T>,
+	on: Box<dyn Predicate + Send + Sync + 'a>,
+	phantom: PhantomData<J>,
+}
+impl<'a, T: EntityExt, J: JoinResult> JoinedSelectBuilder<'a, T, J> {
+	fn new(select: SelectBuilder<'a, T>, on: impl Predicate + Send + Sync + 'a) -> Self {
+		Self { select, on: Box::new(on), phantom: PhantomData }
+	}
+
+	/// Restricts the rows this query matches, with fields of either entity. Calling it again ANDs the new predicate
+	/// onto the previous ones.
+	pub fn filter(mut self, predicate: impl Predicate + Send + Sync + 'a) -> Self {
+		self.select = self.select.filter(predicate);
+		self
+	}
+
+	pub fn order_by(mut self, order: OrderBy<'a>) -> Self {
+		self.select = self.select.order_by(order);
+		self
+	}
+
+	pub fn limit(mut self, limit: i64) -> Self {
+		self.select = self.select.limit(limit);
+		self
+	}
+
+	pub fn offset(mut self, offset: i64) -> Self {
+		self.select = self.select.offset(offset);
+		self
+	}
+
+	pub async fn fetch_all(mut self) -> Result<Vec<(Arc<RwLock<T>>, J)>> {
+		let sql = self.build_select();
+		let query = self.select.clauses.bind_to(self.on.bind_to(sql.to_query()));
+		let rows = self.connection()?.fetch_all(query).await?;
+		let mut pairs = Vec::with_capacity(rows.len());
+		for row in &rows {
+			pairs.push(self.load(row).await?);
+		}
+		let entities = pairs.iter().map(|(entity, _)| entity.clone()).collect::<Vec<_>>();
+		self.select.load_includes(&entities).await?;
+		Ok(pairs)
+	}
+
+	pub async fn fetch_one(mut self) -> Result<(Arc<RwLock<T>>, J)> {
+		let sql = self.build_select();
+		let query = self.select.clauses.bind_to(self.on.bind_to(sql.to_query()));
+		let row = self.connection()?.fetch_one(query).await?;
+		let pair = self.load(&row).await?;
+		self.select.load_includes(slice::from_ref(&pair.0)).await?;
+		Ok(pair)
+	}
+
+	pub async fn fetch_optional(mut self) -> Result<Option<(Arc<RwLock<T>>, J)>> {
+		let sql = self.build_select();
+		let query = self.select.clauses.bind_to(self.on.bind_to(sql.to_query()));
+		let row = self.connection()?.fetch_optional(query).await?;
+		let pair = match row {
+			Some(row) => Some(self.load(&row).await?),
+			None => None,
+		};
+		if let Some((entity, _)) = &pair {
+			self.select.load_includes(slice::from_ref(entity)).await?;
+		}
+		Ok(pair)
+	}
+
+	/// Like `fetch_optional`, but limits the query to a single row instead of expecting the filter to match at most
+	/// one.
+	pub async fn first(self) -> Result<Option<(Arc<RwLock<T>>, J)>> {
+		self.limit(1).fetch_optional().await
+	}
+
+	// columns are qualified by table name alone, so a table can't be told apart from itself
+	fn connection(&self) -> Result<DbConnection> {
+		if TypeId::of::<T>() == TypeId::of::<J::Entity>() {
+			bail!("{} can't be joined to itself", T::META.table_name);
+		}
+		self.select.connection()
+	}
+
+	fn build_select(&self) -> SqlBuilder {
+		let mut sql = SqlBuilder::new();
+		let columns = SelectBuilder::<T>::qualified_columns().chain(SelectBuilder::<J::Entity>::qualified_columns());
+		write!(
+			sql,
+			"SELECT {} FROM \"{}\" {} \"{}\" ON ",
+			columns.collect::<Vec<_>>().join(", "),
+			T::META.table_name,
+			J::JOIN,
+			J::Entity::META.table_name,
+		)
+		.unwrap();
+		self.on.push_to(&mut sql);
+		self.select.clauses.push_to(&mut sql);
+		sql
+	}
+
+	// only the rows of `T` are locked, so only its entities are refreshed
+	async fn load(&mut self, row: &PgRow) -> Result<(Arc<RwLock<T>>, J)> {
+		let mut entity = T::new();
+		DbContext::load_qualified_entity(&mut entity, row)?;
+		let entity = self.select.track(entity).await?;
+		Ok((entity, J::load(self.select.db_context, row)?))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::entity;
+
+	entity!(
+		#[unique(slug)]
+		Page {
+			#[entity_field(primary_key)]
+			id: i32,
+			slug: String,
+			title: String,
+			#[entity_field(version)]
+			version: i32,
+		}
+	);
+
+	fn fields(names: &[&str]) -> Vec<&'static FieldMeta> {
+		names.iter().map(|name| Page::META.fields.get(*name).unwrap()).collect()
+	}
+
+	#[test]
+	fn update_of_modified_columns() {
+		let on_conflict = OnConflict { target: vec!["slug"], action: ConflictAction::Update(None) };
+		assert_eq!(
+			on_conflict.to_sql(Page::META, &fields(&["slug", "title", "version"])),
+			" ON CONFLICT (\"slug\") DO UPDATE SET \"title\" = EXCLUDED.\"title\", \"version\" = \"page\".\"version\" \
+			 + 1"
+		);
+	}
+
+	#[test]
+	fn update_of_given_columns() {
+		let on_conflict = OnConflict { target: vec!["id"], action: ConflictAction::Update(Some(vec!["slug"])) };
+		assert_eq!(
+			on_conflict.to_sql(Page::META, &fields(&["id", "slug", "title"])),
+			" ON CONFLICT (\"id\") DO UPDATE SET \"slug\" = EXCLUDED.\"slug\", \"version\" = \"page\".\"version\" + 1"
+		);
+	}
+
+	#[test]
+	fn insert_of_modified_fields() {
+		let sql = DbContext::insert_sql(Page::META, &fields(&["slug", "title"]), None);
+		assert!(sql.starts_with("INSERT INTO \"page\" (\"slug\", \"title\") VALUES ($1, $2) RETURNING \""), "{}", sql);
+	}
+
+	#[test]
+	fn insert_of_default_values() {
+		let sql = DbContext::insert_sql(Page::META, &[], None);
+		assert!(sql.starts_with("INSERT INTO \"page\" DEFAULT VALUES RETURNING \""), "{}", sql);
+
+		let on_conflict = OnConflict { target: vec![], action: ConflictAction::Nothing };
+		let sql = DbContext::insert_sql(Page::META, &[], Some(&on_conflict));
+		assert!(sql.starts_with("INSERT INTO \"page\" DEFAULT VALUES ON CONFLICT DO NOTHING RETURNING \""), "{}", sql);
+		assert!(sql.ends_with(INSERTED_COLUMN), "{}", sql);
+	}
+
+	#[test]
+	fn do_nothing() {
+		let on_conflict = OnConflict { target: vec![], action: ConflictAction::Nothing };
+		assert_eq!(on_conflict.to_sql(Page::META, &fields(&["slug"])), " ON CONFLICT DO NOTHING");
+
+		let on_conflict = OnConflict { target: vec!["slug"], action: ConflictAction::Nothing };
+		assert_eq!(on_conflict.to_sql(Page::META, &fields(&["slug"])), " ON CONFLICT (\"slug\") DO NOTHING");
+	}
+
+	#[test]
+	fn conflict_targets() {
+		let target = |target: Vec<&'static str>| OnConflict { target, action: ConflictAction::Update(None) };
+		assert!(target(vec!["id"]).check(Page::META).is_ok());
+		assert!(target(vec!["slug"]).check(Page::META).is_ok());
+		assert!(target(vec!["title"]).check(Page::META).is_err());
+		assert!(target(vec!["id", "slug"]).check(Page::META).is_err());
+		assert!(target(vec![]).check(Page::META).is_err());
+
+		let on_conflict = OnConflict { target: vec![], action: ConflictAction::Nothing };
+		assert!(on_conflict.check(Page::META).is_ok());
 	}
 }
@@ -1,4 +1,21 @@
+use crate::EntityField;
+use anyhow::Result;
+use bevy_reflect::Reflect;
 use phf::Map;
+#[cfg(feature = "json")]
+use serde::{de::DeserializeOwned, Serialize};
+#[cfg(feature = "json")]
+use sqlx::types::Json;
+use sqlx::{
+	postgres::{PgArguments, PgHasArrayType, PgRow},
+	query::Query,
+	Decode, Encode, Postgres, Row, Type,
+};
+use std::{
+	any::type_name,
+	fmt::{self, Debug},
+	marker::PhantomData,
+};
 
 pub type Entities = &'static Map<&'static str, &'static EntityMeta>;
 
@@ -6,20 +23,228 @@ pub struct EntityMeta {
 	pub table_name: &'static str,
 	pub fields: Map<&'static str, FieldMeta>,
 	pub primary_key: &'static [&'static str],
+	pub foreign_keys: &'static [ForeignKey],
+	/// The columns of each unique constraint, as declared with `#[unique(...)]`.
+	pub unique: &'static [&'static [&'static str]],
+	pub version: Option<Version>,
+}
+
+/// The field of an entity declared with `#[entity_field(version)]`.
+#[derive(Debug, Clone, Copy)]
+pub struct Version {
+	pub column: &'static str,
+	/// Returns the SQL expression for the version after the one in a column, given the column's quoted name.
+	pub next: fn(&str) -> String,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct FieldMeta {
 	pub name: &'static str,
-	pub ty: FieldType,
+	pub ty: &'static dyn DynFieldType,
 	pub optional: bool,
 	pub identity_generation: Option<IdentityGeneration>,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum FieldType {
-	I32,
-	String,
+/// A Rust type that can be stored in an entity field. Implement this for domain types, such as newtypes or Postgres
+/// enums, to use them in entities without changing nice-orm.
+pub trait FieldType:
+	for<'q> Encode<'q, Postgres> + for<'r> Decode<'r, Postgres> + Type<Postgres> + Clone + Send + Sync + 'static
+{
+	/// The name of the column type, as Postgres reports it in `information_schema.columns`. User-defined types, such
+	/// as enums, are reported by their own name.
+	const COLUMN_TYPE: &'static str;
+	/// Whether the column accepts `NULL`.
+	const NULLABLE: bool = false;
+	/// The labels of the Postgres enum `COLUMN_TYPE`, if it is one, so migrations can keep the type in sync.
+	const ENUM_VALUES: Option<&'static [&'static str]> = None;
+
+	fn bind<'q>(&'q self, query: Query<'q, Postgres, PgArguments>) -> Query<'q, Postgres, PgArguments> {
+		query.bind(self)
+	}
+
+	fn from_column(row: &PgRow, column: &str) -> Result<Self> {
+		Ok(row.try_get(column)?)
+	}
+
+	fn is_modified(field: &EntityField<Self>) -> bool {
+		field.is_modified()
+	}
+}
+impl<T: FieldType> FieldType for Option<T> {
+	const COLUMN_TYPE: &'static str = T::COLUMN_TYPE;
+	const ENUM_VALUES: Option<&'static [&'static str]> = T::ENUM_VALUES;
+	const NULLABLE: bool = true;
+}
+
+/// A `FieldType` that can also be stored in an array column, as a `Vec` of it.
+pub trait ArrayElement: FieldType + PgHasArrayType {
+	/// The `COLUMN_TYPE` of the array column, such as `integer[]`.
+	const ARRAY_COLUMN_TYPE: &'static str;
+}
+impl<T: ArrayElement> FieldType for Vec<T>
+where
+	Vec<T>: for<'q> Encode<'q, Postgres>,
+{
+	const COLUMN_TYPE: &'static str = T::ARRAY_COLUMN_TYPE;
+	const ENUM_VALUES: Option<&'static [&'static str]> = T::ENUM_VALUES;
+}
+
+macro_rules! impl_field_type {
+	($($ty:ty => ($column_type:literal, $array_column_type:literal)),*) => {
+		$(
+			impl FieldType for $ty {
+				const COLUMN_TYPE: &'static str = $column_type;
+			}
+			impl ArrayElement for $ty {
+				const ARRAY_COLUMN_TYPE: &'static str = $array_column_type;
+			}
+		)*
+	};
+}
+impl_field_type!(
+	i16 => ("smallint", "smallint[]"),
+	i32 => ("integer", "integer[]"),
+	i64 => ("bigint", "bigint[]"),
+	f32 => ("real", "real[]"),
+	f64 => ("double precision", "double precision[]"),
+	bool => ("boolean", "boolean[]"),
+	// postgres has no operators between character varying[] and the text[] that strings are bound as
+	String => ("character varying", "text[]")
+);
+impl FieldType for Vec<u8> {
+	const COLUMN_TYPE: &'static str = "bytea";
+}
+#[cfg(feature = "chrono")]
+impl_field_type!(
+	chrono::DateTime<chrono::Utc> => ("timestamp with time zone", "timestamp with time zone[]"),
+	chrono::NaiveDate => ("date", "date[]")
+);
+#[cfg(feature = "uuid")]
+impl_field_type!(uuid::Uuid => ("uuid", "uuid[]"));
+#[cfg(feature = "decimal")]
+impl_field_type!(rust_decimal::Decimal => ("numeric", "numeric[]"));
+#[cfg(feature = "json")]
+impl_field_type!(serde_json::Value => ("jsonb", "jsonb[]"));
+#[cfg(feature = "json")]
+impl<T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static> FieldType for Json<T> {
+	const COLUMN_TYPE: &'static str = "jsonb";
+}
+
+/// A `FieldType` that can be an entity's version, as declared with `#[entity_field(version)]`.
+pub trait VersionType: FieldType {
+	/// The version of a new entity.
+	fn initial() -> Self;
+	/// The SQL expression for the version after the one in `column`, which is already quoted.
+	fn next(column: &str) -> String;
+}
+macro_rules! impl_counter_version {
+	($($ty:ty),*) => {
+		$(
+			impl VersionType for $ty {
+				fn initial() -> Self {
+					1
+				}
+
+				fn next(column: &str) -> String {
+					format!("{} + 1", column)
+				}
+			}
+		)*
+	};
+}
+impl_counter_version!(i16, i32, i64);
+#[cfg(feature = "chrono")]
+impl VersionType for chrono::DateTime<chrono::Utc> {
+	fn initial() -> Self {
+		chrono::Utc::now()
+	}
+
+	// `now()` is the same throughout a transaction, so it can't be the next version on its own
+	fn next(column: &str) -> String {
+		format!("greatest(now(), {} + interval '1 microsecond')", column)
+	}
+}
+
+/// The type-erased side of `FieldType`, used to handle an entity's fields through its `EntityMeta`. The values passed
+/// in are the entity's `EntityField`s.
+pub trait DynFieldType: Debug + Send + Sync {
+	fn column_type(&self) -> &'static str;
+	fn enum_values(&self) -> Option<&'static [&'static str]>;
+	fn is_modified(&self, value: &dyn Reflect) -> bool;
+	fn bind<'q>(
+		&self,
+		query: Query<'q, Postgres, PgArguments>,
+		value: &'q dyn Reflect,
+	) -> Query<'q, Postgres, PgArguments>;
+	/// Overwrites `value` with `column` of `row`, leaving it `Set`.
+	fn load(&self, value: &mut dyn Reflect, row: &PgRow, column: &str) -> Result<()>;
+}
+
+/// Implements `DynFieldType` for `T`. The entity macro stores a reference to `FieldTypeOf::<T>::NEW` in each field's
+/// `FieldMeta`.
+pub struct FieldTypeOf<T>(PhantomData<fn() -> T>);
+impl<T> FieldTypeOf<T> {
+	pub const NEW: Self = Self(PhantomData);
+}
+impl<T> Debug for FieldTypeOf<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "FieldTypeOf<{}>", type_name::<T>())
+	}
+}
+impl<T: FieldType> DynFieldType for FieldTypeOf<T> {
+	fn column_type(&self) -> &'static str {
+		T::COLUMN_TYPE
+	}
+
+	fn enum_values(&self) -> Option<&'static [&'static str]> {
+		T::ENUM_VALUES
+	}
+
+	fn is_modified(&self, value: &dyn Reflect) -> bool {
+		T::is_modified(value.downcast_ref().unwrap())
+	}
+
+	fn bind<'q>(
+		&self,
+		query: Query<'q, Postgres, PgArguments>,
+		value: &'q dyn Reflect,
+	) -> Query<'q, Postgres, PgArguments> {
+		value.downcast_ref::<EntityField<T>>().unwrap().get().bind(query)
+	}
+
+	fn load(&self, value: &mut dyn Reflect, row: &PgRow, column: &str) -> Result<()> {
+		*value.downcast_mut::<EntityField<T>>().unwrap() = EntityField::Set(T::from_column(row, column)?);
+		Ok(())
+	}
+}
+
+/// A column that references the primary key of another table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForeignKey {
+	pub column: &'static str,
+	pub table: &'static str,
+	pub on_delete: OnDelete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnDelete {
+	NoAction,
+	Restrict,
+	Cascade,
+	SetNull,
+	SetDefault,
+}
+impl OnDelete {
+	pub fn from_name(name: &str) -> Self {
+		match name {
+			"NO ACTION" => Self::NoAction,
+			"RESTRICT" => Self::Restrict,
+			"CASCADE" => Self::Cascade,
+			"SET NULL" => Self::SetNull,
+			"SET DEFAULT" => Self::SetDefault,
+			_ => panic!("Unknown delete rule: {}", name),
+		}
+	}
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -2,18 +2,23 @@ pub mod entity_manager;
 pub mod entity_meta;
 pub mod middleware;
 pub mod query;
+pub mod relation;
 
+pub use anyhow;
 pub use bevy_reflect;
 pub use lazy_static;
 pub use nice_orm_derive::*;
 pub use phf;
 pub use serde;
+pub use sqlx;
+pub use tokio;
 
 use bevy_reflect::{Reflect, Struct};
 use entity_meta::EntityMeta;
 use std::{
 	any::{Any, TypeId},
 	collections::hash_map::DefaultHasher,
+	fmt::Debug,
 	hash::{Hash, Hasher},
 };
 
@@ -52,12 +57,12 @@ impl<T: Clone + Send + Sync + 'static> EntityField<T> {
 	}
 }
 
-pub trait Key {
+pub trait Key: Debug {
 	fn eq(&self, other: &dyn Key) -> bool;
 	fn hash(&self) -> u64;
 	fn as_any(&self) -> &dyn Any;
 }
-impl<T: Eq + Hash + 'static> Key for T {
+impl<T: Eq + Hash + Debug + 'static> Key for T {
 	fn eq(&self, other: &dyn Key) -> bool {
 		if let Some(other) = other.as_any().downcast_ref::<T>() {
 			return self == other;
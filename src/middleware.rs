@@ -2,42 +2,268 @@ pub mod cache;
 
 use std::sync::Arc;
 
-use crate::{entity_meta::EntityMeta, query::Predicate, Entity};
-use anyhow::Result;
+use crate::{
+	entity_meta::EntityMeta,
+	query::{Assignments, SelectClauses},
+	Entity,
+};
+use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
 use futures::future::BoxFuture;
-use sqlx::{Postgres, Transaction};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+	postgres::{PgRow, PgTypeKind},
+	Column, Postgres, Row, Transaction, TypeInfo, ValueRef,
+};
 
-// params: operation, entity_meta, filter
+// params: operation, entity_meta, target column, clauses
 pub type AggregateNext = Box<
 	dyn for<'a> FnOnce(
 			&'static str,
 			&'static EntityMeta,
-			Option<&'a Box<dyn Predicate + Send + Sync + 'a>>,
-		) -> BoxFuture<'a, Result<i64>>
+			Option<&'static str>,
+			&'a SelectClauses<'a>,
+		) -> BoxFuture<'a, Result<AggregateValue>>
 		+ Send
 		+ Sync,
 >;
+// params: entity_meta, assignments, clauses
+pub type BulkUpdateNext = Box<
+	dyn for<'a> FnOnce(&'static EntityMeta, &'a Assignments<'a>, &'a SelectClauses<'a>) -> BoxFuture<'a, Result<u64>>
+		+ Send
+		+ Sync,
+>;
+// params: entity_meta, clauses
+pub type BulkDeleteNext =
+	Box<dyn for<'a> FnOnce(&'static EntityMeta, &'a SelectClauses<'a>) -> BoxFuture<'a, Result<u64>> + Send + Sync>;
 pub type FlushNext = Box<
+	dyn for<'b> FnOnce(&'b mut Transaction<'_, Postgres>, &'b mut dyn Entity) -> BoxFuture<'b, Result<u64>>
+		+ Send
+		+ Sync,
+>;
+pub type FlushManyNext = Box<
+	dyn for<'b, 'c> FnOnce(
+			&'b mut Transaction<'_, Postgres>,
+			&'b mut [&'c mut dyn Entity],
+		) -> BoxFuture<'b, Result<u64>>
+		+ Send
+		+ Sync,
+>;
+pub type UpdateNext = Box<
 	dyn for<'b> FnOnce(&'b mut Transaction<'_, Postgres>, &'b mut dyn Entity) -> BoxFuture<'b, Result<()>>
 		+ Send
 		+ Sync,
 >;
+pub type RemoveNext = Box<
+	dyn for<'b> FnOnce(&'b mut Transaction<'_, Postgres>, &'b mut dyn Entity) -> BoxFuture<'b, Result<u64>>
+		+ Send
+		+ Sync,
+>;
 
 #[async_trait]
 pub trait EventListener {
+	/// Runs an aggregate, such as `SelectBuilder::count`. Aggregates over locked rows, or inside a transaction from
+	/// `DbContext::begin`, skip this.
 	async fn aggregate(
 		self: Arc<Self>,
 		operation: &'static str,
 		entity_meta: &'static EntityMeta,
-		filter: Option<&'async_trait Box<dyn Predicate + Send + Sync + 'async_trait>>,
+		target: Option<&'static str>,
+		clauses: &'async_trait SelectClauses<'async_trait>,
 		next: AggregateNext,
-	) -> Result<i64>;
+	) -> Result<AggregateValue>;
 
+	/// Runs `SelectBuilder::update`, returning the number of rows it changed.
+	async fn bulk_update(
+		self: Arc<Self>,
+		entity_meta: &'static EntityMeta,
+		assignments: &'async_trait Assignments<'async_trait>,
+		clauses: &'async_trait SelectClauses<'async_trait>,
+		next: BulkUpdateNext,
+	) -> Result<u64>;
+
+	/// Runs `SelectBuilder::delete`, returning the number of rows it deleted.
+	async fn bulk_delete(
+		self: Arc<Self>,
+		entity_meta: &'static EntityMeta,
+		clauses: &'async_trait SelectClauses<'async_trait>,
+		next: BulkDeleteNext,
+	) -> Result<u64>;
+
+	/// Inserts an entity, returning the number of rows it inserted. That's 0 when an upsert updated an existing row
+	/// instead.
 	async fn flush(
 		self: Arc<Self>,
 		transaction: &mut Transaction<'_, Postgres>,
 		entity: &mut dyn Entity,
 		next: FlushNext,
+	) -> Result<u64>;
+
+	/// Like `flush`, for several entities of the same type that are inserted with a single statement.
+	async fn flush_many(
+		self: Arc<Self>,
+		transaction: &mut Transaction<'_, Postgres>,
+		entities: &mut [&mut dyn Entity],
+		next: FlushManyNext,
+	) -> Result<u64>;
+
+	async fn update(
+		self: Arc<Self>,
+		transaction: &mut Transaction<'_, Postgres>,
+		entity: &mut dyn Entity,
+		next: UpdateNext,
 	) -> Result<()>;
+
+	/// Deletes an entity, returning the number of rows it deleted. That's 0 when the row was already gone.
+	async fn remove(
+		self: Arc<Self>,
+		transaction: &mut Transaction<'_, Postgres>,
+		entity: &mut dyn Entity,
+		next: RemoveNext,
+	) -> Result<u64>;
+
+	/// Runs after a transaction that the other hooks ran in has ended, with the tables written in it, whether it was
+	/// committed or rolled back. The hooks run before the commit, so what they saw may never have happened.
+	async fn transaction_end(self: Arc<Self>, tables: &[&'static str]) -> Result<()>;
+}
+
+/// The result of an aggregate query, before it's converted to the type the caller asked for. Serializes as a plain
+/// JSON value, so integers stay usable with commands like redis's `INCR`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AggregateValue {
+	Null,
+	Bool(bool),
+	Int(i64),
+	Float(f64),
+	String(String),
+	/// The result of a grouped query, as `(key, value)` pairs.
+	Groups(Vec<(AggregateValue, AggregateValue)>),
+}
+impl AggregateValue {
+	/// Decodes a column of `row`, picking a variant based on the column's type. Types without a variant of their own,
+	/// such as timestamps and enums, are decoded as strings that their `FromAggregateValue` implementations parse.
+	pub fn decode(row: &PgRow, index: usize) -> Result<Self> {
+		if row.try_get_raw(index)?.is_null() {
+			return Ok(Self::Null);
+		}
+		let type_info = row.column(index).type_info();
+		Ok(match type_info.name() {
+			"BOOL" => Self::Bool(row.try_get(index)?),
+			"INT2" => Self::Int(row.try_get::<i16, _>(index)?.into()),
+			"INT4" => Self::Int(row.try_get::<i32, _>(index)?.into()),
+			"INT8" => Self::Int(row.try_get(index)?),
+			"FLOAT4" => Self::Float(row.try_get::<f32, _>(index)?.into()),
+			"FLOAT8" => Self::Float(row.try_get(index)?),
+			"TEXT" | "VARCHAR" | "BPCHAR" | "NAME" => Self::String(row.try_get(index)?),
+			#[cfg(feature = "decimal")]
+			"NUMERIC" => Self::String(row.try_get::<rust_decimal::Decimal, _>(index)?.to_string()),
+			#[cfg(feature = "chrono")]
+			"TIMESTAMPTZ" => Self::String(row.try_get::<chrono::DateTime<chrono::Utc>, _>(index)?.to_rfc3339()),
+			#[cfg(feature = "chrono")]
+			"DATE" => Self::String(row.try_get::<chrono::NaiveDate, _>(index)?.to_string()),
+			#[cfg(feature = "uuid")]
+			"UUID" => Self::String(row.try_get::<uuid::Uuid, _>(index)?.to_string()),
+			// enum values are sent as their labels
+			_ if matches!(type_info.kind(), PgTypeKind::Enum(_)) => Self::String(row.try_get_unchecked(index)?),
+			name => bail!("Unsupported aggregate result type: {}", name),
+		})
+	}
+
+	/// Decodes rows of `(key, value)` columns.
+	pub fn decode_groups(rows: &[PgRow]) -> Result<Self> {
+		let groups = rows.iter().map(|row| Ok((Self::decode(row, 0)?, Self::decode(row, 1)?)));
+		Ok(Self::Groups(groups.collect::<Result<_>>()?))
+	}
+}
+
+pub trait FromAggregateValue: Sized {
+	fn from_aggregate_value(value: AggregateValue) -> Result<Self>;
+}
+impl FromAggregateValue for bool {
+	fn from_aggregate_value(value: AggregateValue) -> Result<Self> {
+		match value {
+			AggregateValue::Bool(x) => Ok(x),
+			value => Err(anyhow!("Expected a boolean aggregate, got {:?}", value)),
+		}
+	}
+}
+impl FromAggregateValue for i16 {
+	fn from_aggregate_value(value: AggregateValue) -> Result<Self> {
+		Ok(i64::from_aggregate_value(value)?.try_into()?)
+	}
+}
+impl FromAggregateValue for i32 {
+	fn from_aggregate_value(value: AggregateValue) -> Result<Self> {
+		Ok(i64::from_aggregate_value(value)?.try_into()?)
+	}
+}
+impl FromAggregateValue for i64 {
+	fn from_aggregate_value(value: AggregateValue) -> Result<Self> {
+		match value {
+			AggregateValue::Int(x) => Ok(x),
+			value => Err(anyhow!("Expected an integer aggregate, got {:?}", value)),
+		}
+	}
+}
+impl FromAggregateValue for f64 {
+	fn from_aggregate_value(value: AggregateValue) -> Result<Self> {
+		match value {
+			AggregateValue::Float(x) => Ok(x),
+			AggregateValue::Int(x) => Ok(x as f64),
+			value => Err(anyhow!("Expected a numeric aggregate, got {:?}", value)),
+		}
+	}
+}
+impl FromAggregateValue for f32 {
+	fn from_aggregate_value(value: AggregateValue) -> Result<Self> {
+		Ok(f64::from_aggregate_value(value)? as f32)
+	}
+}
+impl FromAggregateValue for String {
+	fn from_aggregate_value(value: AggregateValue) -> Result<Self> {
+		match value {
+			AggregateValue::String(x) => Ok(x),
+			value => Err(anyhow!("Expected a string aggregate, got {:?}", value)),
+		}
+	}
+}
+/// Parses a type that `AggregateValue::decode` decodes as a string.
+#[allow(unused_macros)]
+macro_rules! impl_from_string_aggregate {
+	($($ty:ty),*) => {
+		$(
+			impl FromAggregateValue for $ty {
+				fn from_aggregate_value(value: AggregateValue) -> Result<Self> {
+					Ok(String::from_aggregate_value(value)?.parse()?)
+				}
+			}
+		)*
+	};
+}
+#[cfg(feature = "decimal")]
+impl_from_string_aggregate!(rust_decimal::Decimal);
+#[cfg(feature = "chrono")]
+impl_from_string_aggregate!(chrono::DateTime<chrono::Utc>, chrono::NaiveDate);
+#[cfg(feature = "uuid")]
+impl_from_string_aggregate!(uuid::Uuid);
+
+impl<K: FromAggregateValue, V: FromAggregateValue> FromAggregateValue for Vec<(K, V)> {
+	fn from_aggregate_value(value: AggregateValue) -> Result<Self> {
+		match value {
+			AggregateValue::Groups(groups) => groups
+				.into_iter()
+				.map(|(key, value)| Ok((K::from_aggregate_value(key)?, V::from_aggregate_value(value)?)))
+				.collect(),
+			value => Err(anyhow!("Expected a grouped aggregate, got {:?}", value)),
+		}
+	}
+}
+impl<T: FromAggregateValue> FromAggregateValue for Option<T> {
+	fn from_aggregate_value(value: AggregateValue) -> Result<Self> {
+		match value {
+			AggregateValue::Null => Ok(None),
+			value => Ok(Some(T::from_aggregate_value(value)?)),
+		}
+	}
 }
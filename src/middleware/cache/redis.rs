@@ -1,7 +1,10 @@
 use crate::{
 	entity_meta::EntityMeta,
-	middleware::{AggregateNext, EventListener, FlushNext},
-	query::{Predicate, SqlBuilder},
+	middleware::{
+		AggregateNext, AggregateValue, BulkDeleteNext, BulkUpdateNext, EventListener, FlushManyNext, FlushNext,
+		RemoveNext, UpdateNext,
+	},
+	query::{Assignments, SelectClauses, SqlBuilder},
 	Entity,
 };
 use anyhow::Result;
@@ -26,6 +29,45 @@ impl CacheRedis {
 	async fn get_connection(&self) -> Result<Connection> {
 		Ok(self.pool.get().await?)
 	}
+
+	fn count_key(&self, table_name: &str) -> String {
+		format!("{}:{}:COUNT", self.prefix, table_name)
+	}
+
+	/// Drops every cached aggregate of a table, except for `keep`.
+	async fn unlink_aggregates(&self, table_name: &str, keep: Option<&str>) -> Result<()> {
+		let mut redis = self.get_connection().await?;
+		let mut iter: AsyncIter<String> = redis.scan_match(format!("{}:{}:*", self.prefix, table_name)).await?;
+		let mut keys = vec![];
+		while let Some(key) = iter.next_item().await {
+			if Some(&*key) != keep {
+				keys.push(key);
+			}
+		}
+		if !keys.is_empty() {
+			let mut redis = self.get_connection().await?;
+			let _: i64 = redis.unlink(&keys).await?;
+		}
+		Ok(())
+	}
+
+	/// Adds newly inserted rows to the cached count of a table, if there is one, and drops its other aggregates.
+	async fn increment_count(&self, table_name: &str, inserted: u64) -> Result<()> {
+		let key = self.count_key(table_name);
+		if inserted > 0 {
+			// TODO: save and reuse script
+			let script = Script::new(
+				"if redis.call('exists', ARGV[1]) == 1 then
+					return redis.call('incrby', ARGV[1], ARGV[2])
+				end",
+			);
+			let mut redis = self.get_connection().await?;
+			let _: Option<i64> = script.arg(&key).arg(inserted).invoke_async(&mut redis).await?;
+		}
+
+		// even an upsert that only updated rows can change other aggregates
+		self.unlink_aggregates(table_name, Some(&key)).await
+	}
 }
 #[async_trait]
 impl EventListener for CacheRedis {
@@ -33,29 +75,68 @@ impl EventListener for CacheRedis {
 		self: Arc<Self>,
 		operation: &'static str,
 		entity_meta: &'static EntityMeta,
-		filter: Option<&'async_trait Box<dyn Predicate + Send + Sync + 'async_trait>>,
+		target: Option<&'static str>,
+		clauses: &'async_trait SelectClauses<'async_trait>,
 		next: AggregateNext,
-	) -> Result<i64> {
+	) -> Result<AggregateValue> {
 		let mut key = format!("{}:{}:{}", self.prefix, entity_meta.table_name, operation);
-		if let Some(filter) = filter {
-			// TODO: optimize by writing directly to compressor
-			let mut sql = SqlBuilder::new();
-			filter.push_to(&mut sql);
-			let sql = sql.to_string();
+		if let Some(target) = target {
+			write!(key, ":{}", target).unwrap();
+		}
+		// the SQL only has placeholders for the values, so they're part of the key too
+		let mut values = Vec::new();
+		if !clauses.write_values(&mut values) {
+			return next(operation, entity_meta, target, clauses).await;
+		}
+		// TODO: optimize by writing directly to compressor
+		let mut sql = SqlBuilder::new();
+		clauses.push_to(&mut sql);
+		let sql = sql.to_string();
+		if !sql.is_empty() {
 			// TODO: maybe optimize by using a faster compression algorithm?
 			let mut enc = ZlibEncoder::new(Vec::new(), Compression::fast());
 			write!(enc, "{}", sql).unwrap();
+			enc.write_all(&[0]).unwrap();
+			enc.write_all(&values).unwrap();
 			write!(key, ":{}", base64::encode(enc.finish().unwrap())).unwrap();
 		}
 
 		let mut redis = self.get_connection().await?;
-		let mut count: Option<i64> = redis.get(&key).await?;
-		if count.is_none() {
-			count = Some(next(operation, entity_meta, filter).await?);
-			redis.set(&key, count).await?;
+		let cached: Option<String> = redis.get(&key).await?;
+		if let Some(cached) = cached {
+			return Ok(serde_json::from_str(&cached)?);
 		}
 
-		Ok(count.unwrap())
+		let value = next(operation, entity_meta, target, clauses).await?;
+		let _: () = redis.set(&key, serde_json::to_string(&value)?).await?;
+		Ok(value)
+	}
+
+	async fn bulk_update(
+		self: Arc<Self>,
+		entity_meta: &'static EntityMeta,
+		assignments: &'async_trait Assignments<'async_trait>,
+		clauses: &'async_trait SelectClauses<'async_trait>,
+		next: BulkUpdateNext,
+	) -> Result<u64> {
+		let count = next(entity_meta, assignments, clauses).await?;
+
+		// an update never changes the total count
+		let key = self.count_key(entity_meta.table_name);
+		self.unlink_aggregates(entity_meta.table_name, Some(&key)).await?;
+		Ok(count)
+	}
+
+	async fn bulk_delete(
+		self: Arc<Self>,
+		entity_meta: &'static EntityMeta,
+		clauses: &'async_trait SelectClauses<'async_trait>,
+		next: BulkDeleteNext,
+	) -> Result<u64> {
+		let count = next(entity_meta, clauses).await?;
+
+		self.unlink_aggregates(entity_meta.table_name, None).await?;
+		Ok(count)
 	}
 
 	async fn flush(
@@ -63,25 +144,70 @@ impl EventListener for CacheRedis {
 		transaction: &mut Transaction<'_, Postgres>,
 		entity: &mut dyn Entity,
 		next: FlushNext,
+	) -> Result<u64> {
+		let table_name = entity.meta().table_name;
+		let inserted = next(transaction, entity).await?;
+
+		self.increment_count(table_name, inserted).await?;
+		Ok(inserted)
+	}
+
+	async fn flush_many(
+		self: Arc<Self>,
+		transaction: &mut Transaction<'_, Postgres>,
+		entities: &mut [&mut dyn Entity],
+		next: FlushManyNext,
+	) -> Result<u64> {
+		let table_name = entities[0].meta().table_name;
+		let inserted = next(transaction, entities).await?;
+
+		self.increment_count(table_name, inserted).await?;
+		Ok(inserted)
+	}
+
+	async fn update(
+		self: Arc<Self>,
+		transaction: &mut Transaction<'_, Postgres>,
+		entity: &mut dyn Entity,
+		next: UpdateNext,
 	) -> Result<()> {
 		next(transaction, entity).await?;
 
-		let key = format!("{}:{}:{}", self.prefix, entity.meta().table_name, "count");
-		// TODO: save and reuse script
-		let script = Script::new(
-			"if redis.call('exists', ARGV[1]) then
-				return redis.call('incr', ARGV[1])
-			end",
-		);
-		let mut redis = self.get_connection().await?;
-		script.arg(&key).invoke_async(&mut redis).await?;
+		// an update never changes the total count
+		let key = self.count_key(entity.meta().table_name);
+		self.unlink_aggregates(entity.meta().table_name, Some(&key)).await
+	}
 
-		let mut iter: AsyncIter<Vec<String>> = redis.scan_match(&format!("{}:*", key)).await?;
-		while let Some(keys) = iter.next_item().await {
+	async fn remove(
+		self: Arc<Self>,
+		transaction: &mut Transaction<'_, Postgres>,
+		entity: &mut dyn Entity,
+		next: RemoveNext,
+	) -> Result<u64> {
+		let deleted = next(transaction, entity).await?;
+
+		let key = self.count_key(entity.meta().table_name);
+		// a row that was already gone was never part of the cached count
+		if deleted > 0 {
+			// TODO: save and reuse script
+			let script = Script::new(
+				"if redis.call('exists', ARGV[1]) == 1 then
+					return redis.call('decrby', ARGV[1], ARGV[2])
+				end",
+			);
 			let mut redis = self.get_connection().await?;
-			let _: i8 = redis.unlink(&keys).await?;
+			let _: Option<i64> = script.arg(&key).arg(deleted).invoke_async(&mut redis).await?;
 		}
 
+		self.unlink_aggregates(entity.meta().table_name, Some(&key)).await?;
+		Ok(deleted)
+	}
+
+	async fn transaction_end(self: Arc<Self>, tables: &[&'static str]) -> Result<()> {
+		// the count may have been changed by writes that were rolled back
+		for table_name in tables {
+			self.unlink_aggregates(table_name, None).await?;
+		}
 		Ok(())
 	}
 }
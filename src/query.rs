@@ -1,6 +1,8 @@
-use crate::EntityExt;
+use crate::{entity_meta::EntityMeta, EntityExt};
+use anyhow::{bail, Result};
 use sqlx::{
-	postgres::{PgArguments, PgTypeInfo},
+	encode,
+	postgres::{PgArgumentBuffer, PgArguments, PgTypeInfo},
 	query,
 	query::Query,
 	Encode, Postgres, Type,
@@ -17,7 +19,7 @@ pub struct SqlBuilder {
 }
 impl SqlBuilder {
 	pub fn new() -> Self {
-		Self { sql: String::new(), param_types: Vec::new(), param_idx: 0 }
+		Self { sql: String::new(), param_types: Vec::new(), param_idx: 1 }
 	}
 
 	pub fn push(&mut self, sql: &str) {
@@ -30,14 +32,20 @@ impl SqlBuilder {
 		self.param_idx += 1;
 	}
 
+	#[allow(clippy::inherent_to_string)]
 	pub fn to_string(self) -> String {
 		self.sql.clone()
 	}
 
-	pub fn to_query(&self) -> Query<Postgres, PgArguments> {
+	pub fn to_query(&self) -> Query<'_, Postgres, PgArguments> {
 		query(&self.sql)
 	}
 }
+impl Default for SqlBuilder {
+	fn default() -> Self {
+		Self::new()
+	}
+}
 impl Write for SqlBuilder {
 	fn write_str(&mut self, s: &str) -> fmt::Result {
 		self.sql.push_str(s);
@@ -48,26 +56,547 @@ impl Write for SqlBuilder {
 pub trait Expression {
 	fn push_to(&self, query: &mut SqlBuilder);
 	fn bind_to<'a>(&'a self, query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments>;
+
+	/// Writes an encoding of the values `bind_to` binds, which tells apart expressions with the same SQL, such as for
+	/// cache keys. Returns `false` if some value can't be encoded, which is the default for expressions outside
+	/// nice-orm.
+	fn write_values(&self, _out: &mut Vec<u8>) -> bool {
+		false
+	}
+}
+
+/// Implements `Expression::write_values` for a single bound value, as its length-prefixed binary encoding.
+pub fn write_value<T: for<'q> Encode<'q, Postgres>>(value: &T, out: &mut Vec<u8>) -> bool {
+	let mut buf = PgArgumentBuffer::default();
+	match value.encode_by_ref(&mut buf) {
+		encode::IsNull::Yes => out.extend_from_slice(&(-1i32).to_be_bytes()),
+		encode::IsNull::No => {
+			out.extend_from_slice(&(buf.len() as i32).to_be_bytes());
+			out.extend_from_slice(&buf);
+		},
+	}
+	true
+}
+
+macro_rules! impl_value_expression {
+	($($ty:ty),*) => {
+		$(
+			impl Expression for $ty {
+				fn push_to(&self, query: &mut SqlBuilder) {
+					query.push_param(<Self as Type<Postgres>>::type_info());
+				}
+
+				fn bind_to<'a>(&'a self, query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+					query.bind(self).persistent(true)
+				}
+
+				fn write_values(&self, out: &mut Vec<u8>) -> bool {
+					write_value(self, out)
+				}
+			}
+		)*
+	};
+}
+impl_value_expression!(i16, i32, i64, f32, f64, bool, String);
+#[cfg(feature = "chrono")]
+impl_value_expression!(chrono::DateTime<chrono::Utc>, chrono::NaiveDate);
+#[cfg(feature = "uuid")]
+impl_value_expression!(uuid::Uuid);
+#[cfg(feature = "decimal")]
+impl_value_expression!(rust_decimal::Decimal);
+#[cfg(feature = "json")]
+impl_value_expression!(serde_json::Value);
+
+/// Vectors are bound as a single array parameter.
+impl<T: Send + Sync> Expression for Vec<T>
+where
+	Vec<T>: Type<Postgres> + for<'q> Encode<'q, Postgres>,
+{
+	fn push_to(&self, query: &mut SqlBuilder) {
+		query.push_param(<Self as Type<Postgres>>::type_info());
+	}
+
+	fn bind_to<'a>(&'a self, query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+		query.bind(self).persistent(true)
+	}
+
+	fn write_values(&self, out: &mut Vec<u8>) -> bool {
+		write_value(self, out)
+	}
 }
-impl Expression for String {
+
+/// Options are bound as a single parameter, which is `NULL` for `None`.
+impl<T: Send + Sync> Expression for Option<T>
+where
+	Option<T>: Type<Postgres> + for<'q> Encode<'q, Postgres>,
+{
 	fn push_to(&self, query: &mut SqlBuilder) {
-		query.push_param(Self::type_info());
+		query.push_param(<Self as Type<Postgres>>::type_info());
 	}
 
 	fn bind_to<'a>(&'a self, query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
 		query.bind(self).persistent(true)
 	}
+
+	fn write_values(&self, out: &mut Vec<u8>) -> bool {
+		write_value(self, out)
+	}
+}
+
+/// Tuples are written as row constructors, such as `($1, $2)`.
+macro_rules! impl_tuple_expression {
+	($first:ident $(, $rest:ident)*) => {
+		#[allow(non_snake_case)]
+		impl<$first: Expression, $($rest: Expression),*> Expression for ($first, $($rest),*) {
+			fn push_to(&self, query: &mut SqlBuilder) {
+				let ($first, $($rest),*) = self;
+				query.push("(");
+				$first.push_to(query);
+				$(
+					query.push(", ");
+					$rest.push_to(query);
+				)*
+				query.push(")");
+			}
+
+			fn bind_to<'a>(&'a self, mut query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+				let ($first, $($rest),*) = self;
+				query = $first.bind_to(query);
+				$(query = $rest.bind_to(query);)*
+				query
+			}
+
+			fn write_values(&self, out: &mut Vec<u8>) -> bool {
+				let ($first, $($rest),*) = self;
+				$first.write_values(out) $(&& $rest.write_values(out))*
+			}
+		}
+	};
 }
+impl_tuple_expression!(A, B);
+impl_tuple_expression!(A, B, C);
+impl_tuple_expression!(A, B, C, D);
 
-pub trait ExpressionExt: Sized {
-	fn eq<'a, T: Encode<'a, Postgres>>(self, other: T) -> Eq<Self, T> {
+pub trait ExpressionExt: Expression + Sized {
+	fn eq<T: Expression>(self, other: T) -> Eq<Self, T> {
 		Eq(self, other)
 	}
+
+	fn ne<T: Expression>(self, other: T) -> Ne<Self, T> {
+		Ne(self, other)
+	}
+
+	fn lt<T: Expression>(self, other: T) -> Lt<Self, T> {
+		Lt(self, other)
+	}
+
+	fn le<T: Expression>(self, other: T) -> Le<Self, T> {
+		Le(self, other)
+	}
+
+	fn gt<T: Expression>(self, other: T) -> Gt<Self, T> {
+		Gt(self, other)
+	}
+
+	fn ge<T: Expression>(self, other: T) -> Ge<Self, T> {
+		Ge(self, other)
+	}
+
+	/// Matches any of `values`, which are bound as a single array parameter.
+	fn in_list<T, I: IntoIterator<Item = T>>(self, values: I) -> InList<Self, Vec<T>>
+	where
+		Vec<T>: Expression,
+	{
+		InList(self, values.into_iter().collect())
+	}
+
+	fn not_in<T, I: IntoIterator<Item = T>>(self, values: I) -> NotIn<Self, Vec<T>>
+	where
+		Vec<T>: Expression,
+	{
+		NotIn(self, values.into_iter().collect())
+	}
+
+	fn like<T: Expression>(self, pattern: T) -> Like<Self, T> {
+		Like(self, pattern)
+	}
+
+	fn ilike<T: Expression>(self, pattern: T) -> ILike<Self, T> {
+		ILike(self, pattern)
+	}
+
+	/// Matches arrays that contain every element of `other`.
+	fn contains<T: Expression>(self, other: T) -> Contains<Self, T> {
+		Contains(self, other)
+	}
+
+	/// Matches arrays that have at least one element in common with `other`.
+	fn overlaps<T: Expression>(self, other: T) -> Overlaps<Self, T> {
+		Overlaps(self, other)
+	}
+
+	/// Matches arrays that contain `element`.
+	fn includes<T: Expression>(self, element: T) -> InList<T, Self> {
+		InList(element, self)
+	}
+
+	fn between<T: Expression, U: Expression>(self, low: T, high: U) -> Between<Self, T, U> {
+		Between(self, low, high)
+	}
+
+	// named after the SQL operators
+	#[allow(clippy::wrong_self_convention)]
+	fn is_null(self) -> IsNull<Self> {
+		IsNull(self)
+	}
+
+	#[allow(clippy::wrong_self_convention)]
+	fn is_not_null(self) -> IsNotNull<Self> {
+		IsNotNull(self)
+	}
+
+	fn asc<'a>(self) -> OrderBy<'a>
+	where
+		Self: Send + Sync + 'a,
+	{
+		OrderBy { expression: Box::new(self), descending: false }
+	}
+
+	fn desc<'a>(self) -> OrderBy<'a>
+	where
+		Self: Send + Sync + 'a,
+	{
+		OrderBy { expression: Box::new(self), descending: true }
+	}
 }
 impl<T: Expression> ExpressionExt for T {}
 
 pub trait Predicate: Expression {}
 
+/// An aggregate function call, such as `COUNT(*)`, for use in `having` and `order_by` on grouped queries.
+pub struct Aggregate<T>(&'static str, T);
+impl<T: Expression> Expression for Aggregate<T> {
+	fn push_to(&self, query: &mut SqlBuilder) {
+		query.push(self.0);
+		query.push("(");
+		self.1.push_to(query);
+		query.push(")");
+	}
+
+	fn bind_to<'a>(&'a self, query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+		self.1.bind_to(query)
+	}
+
+	fn write_values(&self, out: &mut Vec<u8>) -> bool {
+		self.1.write_values(out)
+	}
+}
+
+/// The `*` in `COUNT(*)`.
+pub struct All;
+impl Expression for All {
+	fn push_to(&self, query: &mut SqlBuilder) {
+		query.push("*");
+	}
+
+	fn bind_to<'a>(&'a self, query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+		query
+	}
+
+	fn write_values(&self, _out: &mut Vec<u8>) -> bool {
+		true
+	}
+}
+
+pub fn count_all() -> Aggregate<All> {
+	Aggregate("COUNT", All)
+}
+
+pub fn count<T: Expression>(argument: T) -> Aggregate<T> {
+	Aggregate("COUNT", argument)
+}
+
+pub fn sum<T: Expression>(argument: T) -> Aggregate<T> {
+	Aggregate("SUM", argument)
+}
+
+pub fn avg<T: Expression>(argument: T) -> Aggregate<T> {
+	Aggregate("AVG", argument)
+}
+
+pub fn min<T: Expression>(argument: T) -> Aggregate<T> {
+	Aggregate("MIN", argument)
+}
+
+pub fn max<T: Expression>(argument: T) -> Aggregate<T> {
+	Aggregate("MAX", argument)
+}
+
+pub struct OrderBy<'a> {
+	pub expression: Box<dyn Expression + Send + Sync + 'a>,
+	pub descending: bool,
+}
+
+/// A locking clause, which locks the selected rows of one table until the end of the transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lock {
+	pub strength: LockStrength,
+	pub table_name: &'static str,
+	pub wait: LockWait,
+}
+impl Lock {
+	pub fn push_to(&self, query: &mut SqlBuilder) {
+		let strength = match self.strength {
+			LockStrength::Update => "UPDATE",
+			LockStrength::Share => "SHARE",
+		};
+		write!(query, " FOR {} OF \"{}\"", strength, self.table_name).unwrap();
+		match self.wait {
+			LockWait::Wait => {},
+			LockWait::NoWait => query.push(" NOWAIT"),
+			LockWait::SkipLocked => query.push(" SKIP LOCKED"),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockStrength {
+	/// `FOR UPDATE`, which blocks every other lock on the rows.
+	Update,
+	/// `FOR SHARE`, which other transactions can also take, but which keeps them from changing the rows.
+	Share,
+}
+
+/// What a locking clause does about rows that another transaction has already locked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockWait {
+	/// Waits for the other transaction to finish.
+	Wait,
+	/// Fails right away.
+	NoWait,
+	/// Leaves those rows out.
+	SkipLocked,
+}
+
+/// The `SET` clause of a bulk update, as `(column, value)` pairs.
+#[derive(Default)]
+pub struct Assignments<'a>(pub Vec<(&'static str, Box<dyn Expression + Send + Sync + 'a>)>);
+impl<'a> Assignments<'a> {
+	pub fn push_to(&self, query: &mut SqlBuilder) {
+		for (i, (column, value)) in self.0.iter().enumerate() {
+			write!(query, "{}\"{}\" = ", if i == 0 { "" } else { ", " }, column).unwrap();
+			value.push_to(query);
+		}
+	}
+
+	pub fn bind_to<'q>(&'q self, mut query: Query<'q, Postgres, PgArguments>) -> Query<'q, Postgres, PgArguments> {
+		for (_, value) in &self.0 {
+			query = value.bind_to(query);
+		}
+		query
+	}
+}
+
+/// Builds the assignments of `SelectBuilder::update`.
+pub struct UpdateSet<'a, T> {
+	pub assignments: Assignments<'a>,
+	phantom: PhantomData<T>,
+}
+impl<'a, T: EntityExt> UpdateSet<'a, T> {
+	pub(crate) fn new() -> Self {
+		Self { assignments: Assignments::default(), phantom: PhantomData }
+	}
+
+	pub fn set(mut self, field: Field<T>, value: impl Expression + Send + Sync + 'a) -> Self {
+		self.assignments.0.push((field.name, Box::new(value)));
+		self
+	}
+}
+
+/// Everything in a `SELECT` that follows the `FROM` clause.
+#[derive(Default)]
+pub struct SelectClauses<'a> {
+	pub filter: Option<Box<dyn Predicate + Send + Sync + 'a>>,
+	pub group_by: Option<Box<dyn Expression + Send + Sync + 'a>>,
+	pub having: Option<Box<dyn Predicate + Send + Sync + 'a>>,
+	pub order: Vec<OrderBy<'a>>,
+	/// Keyset pagination cursor, compared against the `order` expressions as a row.
+	pub after: Option<Box<dyn Expression + Send + Sync + 'a>>,
+	pub limit: Option<i64>,
+	pub offset: Option<i64>,
+	pub lock: Option<Lock>,
+	/// What to do about locked rows, when it was given before `lock`, which takes it over once it's set.
+	pub lock_wait: Option<LockWait>,
+}
+impl<'a> SelectClauses<'a> {
+	/// Whether these clauses change which rows are returned in ways an aggregate can't express directly, so
+	/// aggregates need to be taken over a subquery instead.
+	pub fn is_windowed(&self) -> bool {
+		!self.order.is_empty() || self.limit.is_some() || self.offset.is_some() || self.lock.is_some()
+	}
+
+	pub fn push_to(&self, query: &mut SqlBuilder) {
+		if let Some(filter) = &self.filter {
+			query.push(" WHERE ");
+			filter.push_to(query);
+		}
+		if let Some(after) = &self.after {
+			query.push(if self.filter.is_some() { " AND " } else { " WHERE " });
+			self.push_keyset_row(query);
+			query.push(if self.keyset_descending() { " < " } else { " > " });
+			after.push_to(query);
+		}
+		if let Some(group_by) = &self.group_by {
+			query.push(" GROUP BY ");
+			group_by.push_to(query);
+		}
+		if let Some(having) = &self.having {
+			query.push(" HAVING ");
+			having.push_to(query);
+		}
+		for (i, order) in self.order.iter().enumerate() {
+			query.push(if i == 0 { " ORDER BY " } else { ", " });
+			order.expression.push_to(query);
+			query.push(if order.descending { " DESC" } else { " ASC" });
+		}
+		if let Some(limit) = self.limit {
+			write!(query, " LIMIT {}", limit).unwrap();
+		}
+		if let Some(offset) = self.offset {
+			write!(query, " OFFSET {}", offset).unwrap();
+		}
+		if let Some(lock) = &self.lock {
+			lock.push_to(query);
+		}
+	}
+
+	pub fn bind_to<'q>(&'q self, mut query: Query<'q, Postgres, PgArguments>) -> Query<'q, Postgres, PgArguments> {
+		if let Some(filter) = &self.filter {
+			query = filter.bind_to(query);
+		}
+		if let Some(after) = &self.after {
+			for order in &self.order {
+				query = order.expression.bind_to(query);
+			}
+			query = after.bind_to(query);
+		}
+		if let Some(group_by) = &self.group_by {
+			query = group_by.bind_to(query);
+		}
+		if let Some(having) = &self.having {
+			query = having.bind_to(query);
+		}
+		for order in &self.order {
+			query = order.expression.bind_to(query);
+		}
+		query
+	}
+
+	/// Like `Expression::write_values`, for every value `bind_to` binds.
+	pub fn write_values(&self, out: &mut Vec<u8>) -> bool {
+		let mut written = true;
+		if let Some(filter) = &self.filter {
+			written &= filter.write_values(out);
+		}
+		if let Some(after) = &self.after {
+			for order in &self.order {
+				written &= order.expression.write_values(out);
+			}
+			written &= after.write_values(out);
+		}
+		if let Some(group_by) = &self.group_by {
+			written &= group_by.write_values(out);
+		}
+		if let Some(having) = &self.having {
+			written &= having.write_values(out);
+		}
+		for order in &self.order {
+			written &= order.expression.write_values(out);
+		}
+		written
+	}
+
+	/// Fails if these clauses can't be written as valid SQL, such as a keyset cursor without an ordering.
+	pub fn check(&self) -> Result<()> {
+		if self.lock_wait.is_some() && self.lock.is_none() {
+			bail!("nowait and skip_locked need for_update or for_share");
+		}
+		if self.after.is_some() {
+			if self.order.is_empty() {
+				bail!("keyset pagination requires an ordering");
+			}
+			if self.order.iter().any(|x| x.descending != self.keyset_descending()) {
+				bail!("keyset pagination requires every ordering to use the same direction");
+			}
+		}
+		Ok(())
+	}
+
+	/// Pushes a `WHERE` clause matching the same rows of `entity_meta`'s table as these clauses, for statements such as
+	/// `UPDATE` that can't take the rest of them. Windowed clauses are applied through a subquery on the primary key.
+	/// Binds the same way as `push_to`.
+	pub fn push_filter_to(&self, query: &mut SqlBuilder, entity_meta: &EntityMeta) {
+		if !self.is_windowed() {
+			self.push_to(query);
+			return;
+		}
+
+		let columns = entity_meta.primary_key.iter().map(|name| format!("\"{}\".\"{}\"", entity_meta.table_name, name));
+		let columns = columns.collect::<Vec<_>>().join(", ");
+		write!(query, " WHERE ({}) IN (SELECT {} FROM \"{}\"", columns, columns, entity_meta.table_name).unwrap();
+		self.push_to(query);
+		query.push(")");
+	}
+
+	fn push_keyset_row(&self, query: &mut SqlBuilder) {
+		if self.order.len() != 1 {
+			query.push("(");
+		}
+		for (i, order) in self.order.iter().enumerate() {
+			if i > 0 {
+				query.push(", ");
+			}
+			order.expression.push_to(query);
+		}
+		if self.order.len() != 1 {
+			query.push(")");
+		}
+	}
+
+	fn keyset_descending(&self) -> bool {
+		self.order.first().map(|x| x.descending).unwrap_or(false)
+	}
+}
+impl<T: Expression + ?Sized> Expression for Box<T> {
+	fn push_to(&self, query: &mut SqlBuilder) {
+		(**self).push_to(query);
+	}
+
+	fn bind_to<'a>(&'a self, query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+		(**self).bind_to(query)
+	}
+
+	fn write_values(&self, out: &mut Vec<u8>) -> bool {
+		(**self).write_values(out)
+	}
+}
+impl<T: Predicate + ?Sized> Predicate for Box<T> {}
+
+pub trait PredicateExt: Predicate + Sized {
+	fn and<T: Predicate>(self, other: T) -> And<Self, T> {
+		And(self, other)
+	}
+
+	fn or<T: Predicate>(self, other: T) -> Or<Self, T> {
+		Or(self, other)
+	}
+
+	fn not(self) -> Not<Self> {
+		Not(self)
+	}
+}
+impl<T: Predicate> PredicateExt for T {}
+
 pub struct Field<T: EntityExt> {
 	pub name: &'static str,
 	pub phantom: PhantomData<T>,
@@ -85,19 +614,309 @@ impl<T: EntityExt> Expression for Field<T> {
 	fn bind_to<'a>(&'a self, query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
 		query
 	}
+
+	fn write_values(&self, _out: &mut Vec<u8>) -> bool {
+		true
+	}
+}
+
+/// The primary key columns of an entity, as a row constructor when the key is composite.
+pub struct PrimaryKey<T: EntityExt>(PhantomData<T>);
+impl<T: EntityExt> PrimaryKey<T> {
+	pub const fn new() -> Self {
+		Self(PhantomData)
+	}
+}
+impl<T: EntityExt> Default for PrimaryKey<T> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+impl<T: EntityExt> Expression for PrimaryKey<T> {
+	fn push_to(&self, query: &mut SqlBuilder) {
+		let columns = T::META.primary_key.iter().map(|name| format!("\"{}\".\"{}\"", T::META.table_name, name));
+		let columns = columns.collect::<Vec<_>>().join(", ");
+		if T::META.primary_key.len() == 1 {
+			query.push(&columns);
+		} else {
+			write!(query, "({})", columns).unwrap();
+		}
+	}
+
+	fn bind_to<'a>(&'a self, query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+		query
+	}
+
+	fn write_values(&self, _out: &mut Vec<u8>) -> bool {
+		true
+	}
+}
+
+macro_rules! impl_comparison {
+	($name:ident, $op:literal) => {
+		pub struct $name<T, U>(T, U);
+		impl<T: Expression, U: Expression> Expression for $name<T, U> {
+			fn push_to(&self, query: &mut SqlBuilder) {
+				self.0.push_to(query);
+				query.push($op);
+				self.1.push_to(query);
+			}
+
+			fn bind_to<'a>(&'a self, mut query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+				query = self.0.bind_to(query);
+				self.1.bind_to(query)
+			}
+
+			fn write_values(&self, out: &mut Vec<u8>) -> bool {
+				self.0.write_values(out) && self.1.write_values(out)
+			}
+		}
+		impl<T: Expression, U: Expression> Predicate for $name<T, U> {}
+	};
 }
+impl_comparison!(Eq, "=");
+impl_comparison!(Ne, "<>");
+impl_comparison!(Lt, "<");
+impl_comparison!(Le, "<=");
+impl_comparison!(Gt, ">");
+impl_comparison!(Ge, ">=");
+impl_comparison!(Like, " LIKE ");
+impl_comparison!(ILike, " ILIKE ");
+impl_comparison!(Contains, " @> ");
+impl_comparison!(Overlaps, " && ");
+
+macro_rules! impl_array_comparison {
+	($name:ident, $op:literal) => {
+		pub struct $name<T, U>(T, U);
+		impl<T: Expression, U: Expression> Expression for $name<T, U> {
+			fn push_to(&self, query: &mut SqlBuilder) {
+				self.0.push_to(query);
+				query.push($op);
+				self.1.push_to(query);
+				query.push(")");
+			}
 
-pub struct Eq<T, U>(T, U);
-impl<T: Expression, U: Expression> Expression for Eq<T, U> {
+			fn bind_to<'a>(&'a self, mut query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+				query = self.0.bind_to(query);
+				self.1.bind_to(query)
+			}
+
+			fn write_values(&self, out: &mut Vec<u8>) -> bool {
+				self.0.write_values(out) && self.1.write_values(out)
+			}
+		}
+		impl<T: Expression, U: Expression> Predicate for $name<T, U> {}
+	};
+}
+impl_array_comparison!(InList, " = ANY(");
+impl_array_comparison!(NotIn, " <> ALL(");
+
+pub struct Between<T, U, V>(T, U, V);
+impl<T: Expression, U: Expression, V: Expression> Expression for Between<T, U, V> {
 	fn push_to(&self, query: &mut SqlBuilder) {
 		self.0.push_to(query);
-		query.push("=");
+		query.push(" BETWEEN ");
 		self.1.push_to(query);
+		query.push(" AND ");
+		self.2.push_to(query);
 	}
 
 	fn bind_to<'a>(&'a self, mut query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
 		query = self.0.bind_to(query);
-		self.1.bind_to(query)
+		query = self.1.bind_to(query);
+		self.2.bind_to(query)
+	}
+
+	fn write_values(&self, out: &mut Vec<u8>) -> bool {
+		self.0.write_values(out) && self.1.write_values(out) && self.2.write_values(out)
+	}
+}
+impl<T: Expression, U: Expression, V: Expression> Predicate for Between<T, U, V> {}
+
+macro_rules! impl_null_check {
+	($name:ident, $op:literal) => {
+		pub struct $name<T>(T);
+		impl<T: Expression> Expression for $name<T> {
+			fn push_to(&self, query: &mut SqlBuilder) {
+				self.0.push_to(query);
+				query.push($op);
+			}
+
+			fn bind_to<'a>(&'a self, query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+				self.0.bind_to(query)
+			}
+
+			fn write_values(&self, out: &mut Vec<u8>) -> bool {
+				self.0.write_values(out)
+			}
+		}
+		impl<T: Expression> Predicate for $name<T> {}
+	};
+}
+impl_null_check!(IsNull, " IS NULL");
+impl_null_check!(IsNotNull, " IS NOT NULL");
+
+macro_rules! impl_connective {
+	($name:ident, $op:literal) => {
+		pub struct $name<T, U>(T, U);
+		impl<T: Predicate, U: Predicate> Expression for $name<T, U> {
+			fn push_to(&self, query: &mut SqlBuilder) {
+				query.push("(");
+				self.0.push_to(query);
+				query.push($op);
+				self.1.push_to(query);
+				query.push(")");
+			}
+
+			fn bind_to<'a>(&'a self, mut query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+				query = self.0.bind_to(query);
+				self.1.bind_to(query)
+			}
+
+			fn write_values(&self, out: &mut Vec<u8>) -> bool {
+				self.0.write_values(out) && self.1.write_values(out)
+			}
+		}
+		impl<T: Predicate, U: Predicate> Predicate for $name<T, U> {}
+	};
+}
+impl_connective!(And, " AND ");
+impl_connective!(Or, " OR ");
+
+pub struct Not<T>(T);
+impl<T: Predicate> Expression for Not<T> {
+	fn push_to(&self, query: &mut SqlBuilder) {
+		query.push("NOT (");
+		self.0.push_to(query);
+		query.push(")");
+	}
+
+	fn bind_to<'a>(&'a self, query: Query<'a, Postgres, PgArguments>) -> Query<'a, Postgres, PgArguments> {
+		self.0.bind_to(query)
+	}
+
+	fn write_values(&self, out: &mut Vec<u8>) -> bool {
+		self.0.write_values(out)
+	}
+}
+impl<T: Predicate> Predicate for Not<T> {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::entity;
+
+	entity!(Item {
+		#[entity_field(primary_key)]
+		id: i32,
+		name: String,
+		rank: i32,
+	});
+
+	fn sql(clauses: &SelectClauses) -> String {
+		let mut query = SqlBuilder::new();
+		clauses.push_to(&mut query);
+		query.to_string()
+	}
+
+	#[test]
+	fn clauses_are_written_in_order() {
+		let clauses = SelectClauses {
+			filter: Some(Box::new(item::name.eq("a".to_string()).and(item::rank.gt(2)))),
+			order: vec![item::rank.desc(), item::id.asc()],
+			limit: Some(10),
+			offset: Some(20),
+			..Default::default()
+		};
+		assert_eq!(
+			sql(&clauses),
+			" WHERE (\"item\".\"name\"=$1 AND \"item\".\"rank\">$2) ORDER BY \"item\".\"rank\" DESC, \"item\".\"id\" \
+			 ASC LIMIT 10 OFFSET 20"
+		);
+	}
+
+	#[test]
+	fn keyset_row_follows_filter() {
+		let clauses = SelectClauses {
+			filter: Some(Box::new(item::name.eq("a".to_string()))),
+			order: vec![item::rank.asc(), item::id.asc()],
+			after: Some(Box::new((3, 7))),
+			..Default::default()
+		};
+		assert!(clauses.check().is_ok());
+		assert_eq!(
+			sql(&clauses),
+			" WHERE \"item\".\"name\"=$1 AND (\"item\".\"rank\", \"item\".\"id\") > ($2, $3) ORDER BY \
+			 \"item\".\"rank\" ASC, \"item\".\"id\" ASC"
+		);
+
+		// the values are written in the order of the parameters they're bound to
+		let mut out = Vec::new();
+		assert!(clauses.write_values(&mut out));
+		let mut expected = Vec::new();
+		write_value(&"a".to_string(), &mut expected);
+		write_value(&3, &mut expected);
+		write_value(&7, &mut expected);
+		assert_eq!(out, expected);
+	}
+
+	#[test]
+	fn descending_keyset_row() {
+		let clauses = SelectClauses { order: vec![item::id.desc()], after: Some(Box::new(5)), ..Default::default() };
+		assert!(clauses.check().is_ok());
+		assert_eq!(sql(&clauses), " WHERE \"item\".\"id\" < $1 ORDER BY \"item\".\"id\" DESC");
+	}
+
+	#[test]
+	fn keyset_requires_one_direction() {
+		let clauses = SelectClauses { after: Some(Box::new(5)), ..Default::default() };
+		assert!(clauses.check().is_err());
+
+		let clauses = SelectClauses {
+			order: vec![item::rank.desc(), item::id.asc()],
+			after: Some(Box::new((3, 7))),
+			..Default::default()
+		};
+		assert!(clauses.check().is_err());
+	}
+
+	#[test]
+	fn locks() {
+		let lock = |strength, wait| {
+			let clauses =
+				SelectClauses { lock: Some(Lock { strength, table_name: "item", wait }), ..Default::default() };
+			sql(&clauses)
+		};
+		assert_eq!(lock(LockStrength::Update, LockWait::Wait), " FOR UPDATE OF \"item\"");
+		assert_eq!(lock(LockStrength::Share, LockWait::NoWait), " FOR SHARE OF \"item\" NOWAIT");
+		assert_eq!(lock(LockStrength::Update, LockWait::SkipLocked), " FOR UPDATE OF \"item\" SKIP LOCKED");
+	}
+
+	#[test]
+	fn lock_wait_requires_lock() {
+		let clauses = SelectClauses { lock_wait: Some(LockWait::NoWait), ..Default::default() };
+		assert!(clauses.check().is_err());
+	}
+
+	#[test]
+	fn filter_of_windowed_clauses_uses_subquery() {
+		let mut query = SqlBuilder::new();
+		let clauses = SelectClauses { filter: Some(Box::new(item::rank.gt(2))), ..Default::default() };
+		clauses.push_filter_to(&mut query, Item::META);
+		assert_eq!(query.to_string(), " WHERE \"item\".\"rank\">$1");
+
+		let mut query = SqlBuilder::new();
+		let clauses = SelectClauses {
+			filter: Some(Box::new(item::rank.gt(2))),
+			order: vec![item::rank.asc()],
+			limit: Some(5),
+			..Default::default()
+		};
+		clauses.push_filter_to(&mut query, Item::META);
+		assert_eq!(
+			query.to_string(),
+			" WHERE (\"item\".\"id\") IN (SELECT \"item\".\"id\" FROM \"item\" WHERE \"item\".\"rank\">$1 ORDER BY \
+			 \"item\".\"rank\" ASC LIMIT 5)"
+		);
 	}
 }
-impl<T: Expression, U: Expression> Predicate for Eq<T, U> {}
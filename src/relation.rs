@@ -0,0 +1,36 @@
+use crate::{entity_manager::DbContext, Entity};
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+pub type RelationFuture<'a> = BoxFuture<'a, Result<()>>;
+pub type LoadRelation<T> = for<'a> fn(&'a mut DbContext, &'a [Arc<RwLock<T>>]) -> RelationFuture<'a>;
+
+/// A relation of `T` that `SelectBuilder::include` can eager-load, such as `account::posts`. The entity macro generates
+/// one for each `belongs_to` and `has_many` attribute.
+pub struct Relation<T> {
+	pub name: &'static str,
+	/// Loads the related entities of every given `T` with a single query, registering them in the context.
+	pub load: LoadRelation<T>,
+}
+impl<T> Clone for Relation<T> {
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+impl<T> Copy for Relation<T> {}
+
+/// A many-to-many relation of `T` to `U`, for `DbContext::link` and `unlink`. The entity macro generates one for each
+/// `many_to_many` attribute, and another for its inverse.
+pub struct ManyToMany<T, U> {
+	pub name: &'static str,
+	/// Builds the join entity linking a `T` to a `U`.
+	pub join: fn(&T, &U) -> Box<dyn Entity>,
+}
+impl<T, U> Clone for ManyToMany<T, U> {
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+impl<T, U> Copy for ManyToMany<T, U> {}